@@ -0,0 +1,156 @@
+use std::io::{self, Write};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+use crate::http_request::HttpRequest;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    Sha256,
+    Md5,
+}
+impl Algorithm {
+    /// The algorithm's name in `Content-Digest` and `Digest` headers, which are compared ignoring case.
+    fn from_name(name: &str) -> Option<Self> {
+        if name.eq_ignore_ascii_case("sha-256") {
+            Some(Self::Sha256)
+        } else if name.eq_ignore_ascii_case("md5") {
+            Some(Self::Md5)
+        } else {
+            None
+        }
+    }
+    fn len(self) -> usize {
+        match self {
+            Algorithm::Sha256 => 32,
+            Algorithm::Md5 => 16,
+        }
+    }
+}
+/// A digest the client sent with an upload, which the upload has to match.
+#[derive(Debug)]
+pub struct Expected {
+    pub algorithm: Algorithm,
+    pub digest: Vec<u8>,
+    /// The header it was sent in.
+    pub header: &'static str,
+}
+/// Reads the digests a client sent in `Content-Digest`, `Digest` or `Content-MD5` headers. Algorithms other than SHA-256 and MD5 are ignored.
+/// # Errors
+/// Returns a message for the client if a digest is malformed.
+pub fn expected_digests(packet: &mut HttpRequest) -> Result<Vec<Expected>, String> {
+    let mut expected = Vec::new();
+    // RFC 9530, e.g. `sha-256=:<base64>:`
+    if let Some(value) = packet.header("Content-Digest") {
+        for (algorithm, digest) in value.split(',').filter_map(|item| item.split_once('=')) {
+            let digest = digest.trim();
+            let digest = digest
+                .strip_prefix(':')
+                .and_then(|digest| digest.strip_suffix(':'))
+                .ok_or_else(|| format!("Invalid Content-Digest \"{digest}\""))?;
+            push_expected(&mut expected, "Content-Digest", algorithm.trim(), digest)?;
+        }
+    }
+    // RFC 3230, e.g. `SHA-256=<base64>`
+    if let Some(value) = packet.header("Digest") {
+        for (algorithm, digest) in value.split(',').filter_map(|item| item.split_once('=')) {
+            push_expected(&mut expected, "Digest", algorithm.trim(), digest.trim())?;
+        }
+    }
+    if let Some(digest) = packet.header("Content-MD5") {
+        push_expected(&mut expected, "Content-MD5", "md5", digest)?;
+    }
+    Ok(expected)
+}
+fn push_expected(
+    expected: &mut Vec<Expected>,
+    header: &'static str,
+    algorithm: &str,
+    digest: &str,
+) -> Result<(), String> {
+    let Some(algorithm) = Algorithm::from_name(algorithm) else {
+        return Ok(()); // Cant be checked
+    };
+    let digest = STANDARD
+        .decode(digest)
+        .ok()
+        .filter(|digest| digest.len() == algorithm.len())
+        .ok_or_else(|| format!("Invalid {header} \"{digest}\""))?;
+    expected.push(Expected {
+        algorithm,
+        digest,
+        header,
+    });
+    Ok(())
+}
+/// Hashes everything written through it. MD5 is only worked out if asked for, as it is only needed to check `Content-MD5`.
+pub struct HashingWriter<W> {
+    inner: W,
+    sha256: Sha256,
+    md5: Option<Md5>,
+}
+/// The digests of an upload, see [`HashingWriter`].
+pub struct Digests {
+    pub sha256: Vec<u8>,
+    pub md5: Option<Vec<u8>>,
+}
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, expected: &[Expected]) -> Self {
+        Self {
+            inner,
+            sha256: Sha256::new(),
+            md5: expected
+                .iter()
+                .any(|expected| expected.algorithm == Algorithm::Md5)
+                .then(Md5::new),
+        }
+    }
+    pub fn finish(self) -> (W, Digests) {
+        let digests = Digests {
+            sha256: self.sha256.finalize().to_vec(),
+            md5: self.md5.map(|md5| md5.finalize().to_vec()),
+        };
+        (self.inner, digests)
+    }
+}
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.sha256.update(&buf[..written]);
+        if let Some(md5) = self.md5.as_mut() {
+            md5.update(&buf[..written]);
+        }
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl Digests {
+    /// The first expected digest which doesnt match, if any.
+    pub fn mismatch<'a>(&self, expected: &'a [Expected]) -> Option<&'a Expected> {
+        expected.iter().find(|expected| {
+            let actual = match expected.algorithm {
+                Algorithm::Sha256 => Some(&self.sha256),
+                Algorithm::Md5 => self.md5.as_ref(),
+            };
+            actual != Some(&expected.digest)
+        })
+    }
+    pub fn sha256_hex(&self) -> String {
+        self.sha256
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect()
+    }
+}
+/// The `Digest` header for a file with this hex encoded SHA-256 digest, e.g. `SHA-256=<base64>`.
+pub fn digest_header(sha256_hex: &str) -> Option<String> {
+    let bytes = (0..sha256_hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(sha256_hex.get(i..i + 2)?, 16).ok())
+        .collect::<Option<Vec<u8>>>()?;
+    Some(format!("SHA-256={}", STANDARD.encode(bytes)))
+}
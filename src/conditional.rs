@@ -0,0 +1,165 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::http_request::HttpRequest;
+
+/// What a request's conditional headers say to do with a file, see RFC 9110 section 13.2.2.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Precondition {
+    Send,
+    /// The client's copy is current, so `304 Not Modified` is sent instead.
+    NotModified,
+    /// `If-Match` or `If-Unmodified-Since` doesnt hold, so `412 Precondition Failed` is sent instead.
+    Failed,
+}
+/// Checks `If-Match`, `If-Unmodified-Since`, `If-None-Match` and `If-Modified-Since` against a file's (strong) ETag and modification time, in the order RFC 9110 gives. Dates which cant be parsed are ignored.
+pub fn evaluate(packet: &mut HttpRequest, etag: &str, modified: SystemTime) -> Precondition {
+    let modified = whole_seconds(modified); // HTTP dates dont go any finer
+    if let Some(if_match) = packet.header("If-Match") {
+        if !etag_matches(if_match, etag, false) {
+            return Precondition::Failed;
+        }
+    } else if let Some(since) = packet
+        .header("If-Unmodified-Since")
+        .and_then(parse_http_date)
+    {
+        if modified > since {
+            return Precondition::Failed;
+        }
+    }
+    if let Some(if_none_match) = packet.header("If-None-Match") {
+        if etag_matches(if_none_match, etag, true) {
+            return Precondition::NotModified;
+        }
+    } else if let Some(since) = packet.header("If-Modified-Since").and_then(parse_http_date) {
+        if modified <= since {
+            return Precondition::NotModified;
+        }
+    }
+    Precondition::Send
+}
+/// Whether a `Range` request should be honoured given its `If-Range` header, which has to name the file's current ETag or exact modification time.
+pub fn if_range_matches(packet: &mut HttpRequest, etag: &str, modified: SystemTime) -> bool {
+    match packet.header("If-Range") {
+        None => true,
+        Some(if_range) if if_range.starts_with('"') => if_range == etag,
+        Some(if_range) => parse_http_date(if_range) == Some(whole_seconds(modified)),
+    }
+}
+/// Whether a list of ETags such as `"abc", W/"def"` includes `etag`, or is `*`. Weak ETags only match with `weak` comparison.
+fn etag_matches(header: &str, etag: &str, weak: bool) -> bool {
+    header.split(',').map(str::trim).any(|candidate| {
+        let candidate = if weak {
+            candidate.strip_prefix("W/").unwrap_or(candidate)
+        } else {
+            candidate
+        };
+        candidate == "*" || candidate == etag
+    })
+}
+/// Parses an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    chrono::DateTime::parse_from_rfc2822(date.trim())
+        .ok()
+        .map(SystemTime::from)
+}
+fn whole_seconds(time: SystemTime) -> SystemTime {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ETAG: &str = "\"abc\"";
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+    const EARLIER: &str = "Sun, 06 Nov 1994 08:49:36 GMT";
+
+    /// `DATE` and a bit, which HTTP dates cant show.
+    fn modified() -> SystemTime {
+        UNIX_EPOCH + Duration::from_millis(784_111_777_500)
+    }
+    fn request(headers: &[&str]) -> HttpRequest {
+        let headers: String = headers
+            .iter()
+            .map(|header| format!("{header}\r\n"))
+            .collect();
+        HttpRequest::from_head(format!("GET /a.txt HTTP/1.1\r\n{headers}\r\n").into_bytes())
+    }
+    fn check(headers: &[&str]) -> Precondition {
+        evaluate(&mut request(headers), ETAG, modified())
+    }
+
+    #[test]
+    fn unconditional() {
+        assert_eq!(check(&[]), Precondition::Send);
+    }
+
+    #[test]
+    fn if_none_match() {
+        assert_eq!(
+            check(&["If-None-Match: \"abc\""]),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            check(&["If-None-Match: \"x\", W/\"abc\""]),
+            Precondition::NotModified
+        );
+        assert_eq!(check(&["If-None-Match: *"]), Precondition::NotModified);
+        assert_eq!(check(&["If-None-Match: \"x\""]), Precondition::Send);
+        // If-Modified-Since is ignored when there is an If-None-Match
+        assert_eq!(
+            check(&[
+                "If-None-Match: \"x\"",
+                &format!("If-Modified-Since: {DATE}")
+            ]),
+            Precondition::Send
+        );
+    }
+
+    #[test]
+    fn if_match() {
+        assert_eq!(check(&["If-Match: \"abc\""]), Precondition::Send);
+        assert_eq!(check(&["If-Match: *"]), Precondition::Send);
+        // Weak ETags never match strongly
+        assert_eq!(check(&["If-Match: W/\"abc\""]), Precondition::Failed);
+        assert_eq!(
+            check(&["If-Match: \"x\"", "If-None-Match: \"abc\""]),
+            Precondition::Failed
+        );
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            check(&[&format!("If-Modified-Since: {DATE}")]),
+            Precondition::NotModified
+        );
+        assert_eq!(
+            check(&[&format!("If-Modified-Since: {EARLIER}")]),
+            Precondition::Send
+        );
+        assert_eq!(
+            check(&[&format!("If-Unmodified-Since: {DATE}")]),
+            Precondition::Send
+        );
+        assert_eq!(
+            check(&[&format!("If-Unmodified-Since: {EARLIER}")]),
+            Precondition::Failed
+        );
+        assert_eq!(check(&["If-Modified-Since: yesterday"]), Precondition::Send);
+    }
+
+    #[test]
+    fn if_range() {
+        let matches = |headers: &[&str]| if_range_matches(&mut request(headers), ETAG, modified());
+        assert!(matches(&[]));
+        assert!(matches(&["If-Range: \"abc\""]));
+        assert!(!matches(&["If-Range: \"x\""]));
+        assert!(!matches(&["If-Range: W/\"abc\""]));
+        assert!(matches(&[&format!("If-Range: {DATE}")]));
+        assert!(!matches(&[&format!("If-Range: {EARLIER}")]));
+    }
+}
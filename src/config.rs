@@ -0,0 +1,675 @@
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+
+use serde::Deserialize;
+
+/// The config file read when `--config` isnt given, if it exists.
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+/// Printed for `--help`, or after a bad argument.
+pub const USAGE: &str = "\
+Usage: poc_project [options]
+
+Settings are read from the TOML file given by --config (or ./config.toml if it exists), and any options below override them.
+
+Options:
+  --config <path>                The TOML config file to read
+  --address <ip:port>            The address to listen on (default 0.0.0.0:80)
+  --workers <count>              Threads handling requests (default 32)
+  --queue-size <count>           Connections waiting for a worker before clients are turned away (default 64)
+  --evented, --threaded          Handle slow clients with epoll, or give each connection a worker (default threaded)
+  --timeout-ms <ms>              How long to wait on a client mid request (default 5000)
+  --keep-alive-timeout-ms <ms>   How long to wait for another request on an open connection (default 3000)
+  --site-path <path>             The personal site's files (default ./site)
+  --files-path <path>            Where uploads are stored (default ./files)
+  --gc, --no-gc                  Delete old uploads (default off)
+  --file-lifetime-secs <secs>    How long uploads are kept for by default (default 3600)
+  --max-lifetime-secs <secs>     The longest lifetime uploaders can ask for (default 604800)
+  --gc-interval-secs <secs>      How often the garbage collector runs (default 3600)
+  --abandoned-secs <secs>        How long a resumable upload can go without a new chunk before it is deleted (default 86400)
+  --max-upload-size <bytes>      The largest upload accepted (default 1 GiB)
+  --id-length <count>            Characters in an upload's folder name (default 8)
+  --id-alphabet <chars>          Characters upload folder names are made from (default base62)
+  --encrypt                      Encrypt uploads with a key only their link holds (default off)
+  --storage <local|memory|s3>    Where uploads are kept (default local)
+  --dedup                        Store identical files once, shared between uploads (default off)
+  --s3-endpoint <url>            The S3 compatible server to keep uploads on (default http://127.0.0.1:9000)
+  --s3-bucket <name>             The bucket to keep uploads in
+  -h, --help                     Show this message
+
+Commands:
+  gc --dry-run                   List the uploads the garbage collector would delete, then exit
+";
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+
+/// The server's settings, loaded once at startup from the config file and command line.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The address the server listens on.
+    pub address: SocketAddr,
+    pub workers: usize,
+    /// Connections waiting for a free worker before clients are turned away.
+    pub queue_size: usize,
+    /// Whether slow clients are handled by epoll rather than tying up a worker.
+    pub evented: bool,
+    /// How long to wait on a client while reading a request or writing a response.
+    pub timeout_ms: u64,
+    /// How long to wait for another request on an open connection.
+    pub keep_alive_timeout_ms: u64,
+    pub site_path: PathBuf,
+    pub files_path: PathBuf,
+    pub gc: GcConfig,
+    pub uploads: UploadConfig,
+    pub storage: StorageConfig,
+    /// The `Cache-Control` header sent with files, by the path they were requested at.
+    pub cache_control: Vec<CacheRule>,
+}
+/// Settings for the garbage collector, the `[gc]` table.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GcConfig {
+    pub enabled: bool,
+    /// How long an upload is kept before it is deleted, unless the uploader asks for another lifetime.
+    pub lifetime_secs: u64,
+    /// The longest lifetime an uploader can ask for.
+    pub max_lifetime_secs: u64,
+    /// How long the garbage collector waits between sweeps.
+    pub interval_secs: u64,
+    /// How long a resumable upload can go without a new chunk before the garbage collector deletes it.
+    pub abandoned_secs: u64,
+}
+/// Settings for uploads, the `[uploads]` table.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UploadConfig {
+    /// The largest body `put` or `post` will accept, anything larger is refused before being written.
+    pub max_size: u64,
+    /// How many characters are in an upload folder's name.
+    pub id_length: usize,
+    /// The characters upload folder names are drawn from.
+    pub id_alphabet: String,
+    /// Whether files are encrypted, with a key derived from a secret in their link which the server doesnt keep.
+    pub encrypt: bool,
+    /// Media types shown in the browser rather than downloaded. Anything else, HTML and SVG in particular, could run scripts as this site.
+    pub inline_types: Vec<String>,
+}
+/// A `Cache-Control` value for every path starting with `prefix`, one of the `[[cache_control]]` tables. Where rules overlap, the longest prefix wins.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CacheRule {
+    pub prefix: String,
+    pub value: String,
+}
+/// Settings for where uploads are kept, the `[storage]` table.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    pub backend: StorageBackend,
+    /// Whether identical files are stored once, shared by every upload of them.
+    pub dedup: bool,
+    pub s3: S3Config,
+}
+/// Settings for the S3 backend, the `[storage.s3]` table.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct S3Config {
+    /// The server holding the bucket, e.g. `http://127.0.0.1:9000`. Only plain HTTP is spoken, so a remote bucket needs a TLS proxy in front of it.
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    /// Put in front of every object's key, so the bucket can be shared.
+    pub prefix: String,
+    /// Read from `AWS_ACCESS_KEY_ID` if left empty.
+    pub access_key: String,
+    /// Read from `AWS_SECRET_ACCESS_KEY` if left empty.
+    pub secret_key: String,
+    /// How much of an upload is held in memory before being sent as one part of a multipart upload.
+    pub part_size: usize,
+    /// How long to wait on the server.
+    pub timeout_ms: u64,
+}
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    /// A folder per upload in `files_path`.
+    #[default]
+    Local,
+    /// In memory, so uploads are lost when the server stops.
+    Memory,
+    /// In an S3 compatible bucket, see [`S3Config`].
+    S3,
+}
+impl FromStr for StorageBackend {
+    type Err = String;
+    fn from_str(backend: &str) -> Result<Self, Self::Err> {
+        match backend {
+            "local" => Ok(Self::Local),
+            "memory" => Ok(Self::Memory),
+            "s3" => Ok(Self::S3),
+            _ => Err("expected \"local\", \"memory\" or \"s3\"".to_owned()),
+        }
+    }
+}
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 80)),
+            workers: 32,
+            queue_size: 64,
+            evented: false,
+            timeout_ms: 5000,
+            keep_alive_timeout_ms: 3000,
+            site_path: PathBuf::from("./site"),
+            files_path: PathBuf::from("./files"),
+            gc: GcConfig::default(),
+            uploads: UploadConfig::default(),
+            storage: StorageConfig::default(),
+            cache_control: vec![
+                // Site assets can be kept, but are checked with a conditional request each time
+                CacheRule {
+                    prefix: "/".to_owned(),
+                    value: "no-cache".to_owned(),
+                },
+                // Uploads on the personal site can expire or run out of downloads, so only the downloader keeps them
+                CacheRule {
+                    prefix: "/files/".to_owned(),
+                    value: "private, no-cache".to_owned(),
+                },
+            ],
+        }
+    }
+}
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            lifetime_secs: 60 * 60,              // 1 Hour
+            max_lifetime_secs: 7 * 24 * 60 * 60, // 1 Week
+            interval_secs: 60 * 60,
+            abandoned_secs: 24 * 60 * 60, // 1 Day
+        }
+    }
+}
+impl Default for UploadConfig {
+    fn default() -> Self {
+        Self {
+            max_size: 1024 * 1024 * 1024, // 1 GiB
+            id_length: 8,
+            id_alphabet: "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ"
+                .to_owned(), // base62
+            encrypt: false,
+            inline_types: [
+                "image/png",
+                "image/jpeg",
+                "image/gif",
+                "image/webp",
+                "image/avif",
+                "application/pdf",
+                "text/plain",
+                "audio/mpeg",
+                "audio/ogg",
+                "video/mp4",
+                "video/webm",
+            ]
+            .map(str::to_owned)
+            .to_vec(),
+        }
+    }
+}
+impl Default for S3Config {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://127.0.0.1:9000".to_owned(),
+            region: "us-east-1".to_owned(),
+            bucket: String::new(),
+            prefix: String::new(),
+            access_key: String::new(),
+            secret_key: String::new(),
+            part_size: 8 * 1024 * 1024, // 8 MiB
+            timeout_ms: 30000,
+        }
+    }
+}
+impl Config {
+    /// Reads the config file, applies the command line `args` over it and checks the result.
+    /// # Errors
+    /// Returns an `InvalidInput` error describing the first bad argument or setting, or the error from reading the config file.
+    pub fn load(args: impl IntoIterator<Item = String>) -> io::Result<Self> {
+        let args = split_flags(args);
+        let config_path = args
+            .iter()
+            .rev()
+            .find(|(flag, _)| flag == "--config")
+            .map(|(_, path)| PathBuf::from(path.as_deref().unwrap_or_default()));
+        let mut config = match config_path {
+            Some(path) => Self::read(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::read(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => Self::default(),
+        };
+        for (flag, value) in args {
+            config.apply(&flag, value)?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
+    fn read(path: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("Failed to read config file \"{}\": {err}", path.display()),
+            )
+        })?;
+        toml::from_str(&text).map_err(|err| {
+            invalid(format!(
+                "Invalid config file \"{}\": {}",
+                path.display(),
+                err.message()
+            ))
+        })
+    }
+    /// Applies a single command line flag.
+    fn apply(&mut self, flag: &str, value: Option<String>) -> io::Result<()> {
+        let value = || {
+            value
+                .clone()
+                .ok_or_else(|| invalid(format!("{flag} needs a value")))
+        };
+        match flag {
+            "--config" => {} // Already read
+            "--address" => self.address = parse(flag, &value()?)?,
+            "--workers" => self.workers = parse(flag, &value()?)?,
+            "--queue-size" => self.queue_size = parse(flag, &value()?)?,
+            "--evented" | "evented" => self.evented = true,
+            "--threaded" => self.evented = false,
+            "--timeout-ms" => self.timeout_ms = parse(flag, &value()?)?,
+            "--keep-alive-timeout-ms" => self.keep_alive_timeout_ms = parse(flag, &value()?)?,
+            "--site-path" => self.site_path = PathBuf::from(value()?),
+            "--files-path" => self.files_path = PathBuf::from(value()?),
+            "--gc" | "gc" => self.gc.enabled = true,
+            "--no-gc" => self.gc.enabled = false,
+            "--file-lifetime-secs" => self.gc.lifetime_secs = parse(flag, &value()?)?,
+            "--max-lifetime-secs" => self.gc.max_lifetime_secs = parse(flag, &value()?)?,
+            "--gc-interval-secs" => self.gc.interval_secs = parse(flag, &value()?)?,
+            "--abandoned-secs" => self.gc.abandoned_secs = parse(flag, &value()?)?,
+            "--max-upload-size" => self.uploads.max_size = parse(flag, &value()?)?,
+            "--id-length" => self.uploads.id_length = parse(flag, &value()?)?,
+            "--id-alphabet" => self.uploads.id_alphabet = value()?,
+            "--encrypt" => self.uploads.encrypt = true,
+            "--storage" => self.storage.backend = parse(flag, &value()?)?,
+            "--dedup" => self.storage.dedup = true,
+            "--s3-endpoint" => self.storage.s3.endpoint = value()?,
+            "--s3-bucket" => self.storage.s3.bucket = value()?,
+            _ => return Err(invalid(format!("Unknown argument \"{flag}\""))),
+        }
+        Ok(())
+    }
+    /// Checks every setting can be used, and resolves the paths so they dont depend on the working directory later.
+    fn validate(&mut self) -> io::Result<()> {
+        if self.workers == 0 {
+            return Err(invalid("workers must be at least 1".to_owned()));
+        }
+        if self.timeout_ms == 0 || self.keep_alive_timeout_ms == 0 {
+            return Err(invalid("Timeouts must be at least 1ms".to_owned()));
+        }
+        if self.gc.lifetime_secs > self.gc.max_lifetime_secs {
+            return Err(invalid(
+                "gc.lifetime_secs cannot be longer than gc.max_lifetime_secs".to_owned(),
+            ));
+        }
+        if self.gc.interval_secs == 0 {
+            return Err(invalid("gc.interval_secs must be at least 1".to_owned()));
+        }
+        if self.uploads.id_length == 0 {
+            return Err(invalid("uploads.id_length must be at least 1".to_owned()));
+        }
+        let alphabet = self.uploads.id_alphabet.as_bytes();
+        if alphabet.len() < 2
+            || !alphabet
+                .iter()
+                .all(|char| char.is_ascii_alphanumeric() || *char == b'-' || *char == b'_')
+            || (1..alphabet.len()).any(|i| alphabet[i..].contains(&alphabet[i - 1]))
+        {
+            return Err(invalid(
+                "uploads.id_alphabet must be at least 2 different letters, digits, \"-\" or \"_\""
+                    .to_owned(),
+            ));
+        }
+        if let Some(media_type) = self.uploads.inline_types.iter().find(|media_type| {
+            media_type.contains(';')
+                || media_type
+                    .split('/')
+                    .filter(|part| !part.is_empty())
+                    .count()
+                    != 2
+        }) {
+            return Err(invalid(format!(
+                "uploads.inline_types has \"{media_type}\", which isnt a media type like \"image/png\""
+            )));
+        }
+        if self.storage.backend == StorageBackend::S3 {
+            self.storage.s3.validate()?;
+        }
+        for rule in &self.cache_control {
+            if !rule.prefix.starts_with('/') || rule.value.contains(['\r', '\n']) {
+                return Err(invalid(format!(
+                    "cache_control prefix \"{}\" must start with \"/\", and its value must be a single line",
+                    rule.prefix
+                )));
+            }
+        }
+        self.site_path = canonical_dir("site_path", &self.site_path)?;
+        self.files_path = canonical_dir("files_path", &self.files_path)?;
+        Ok(())
+    }
+    /// Makes `self` the config returned by [`Config::get`]. Only the first call has any effect.
+    pub fn install(self) {
+        let _ = CONFIG.set(self);
+    }
+    /// The server's settings.
+    /// # Panics
+    /// Panics if called before [`Config::install`].
+    pub fn get() -> &'static Self {
+        CONFIG.get().expect("Config should be installed at startup")
+    }
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+    pub fn keep_alive_timeout(&self) -> Duration {
+        Duration::from_millis(self.keep_alive_timeout_ms)
+    }
+    /// The `Cache-Control` header for a file requested at `path`, from the rule with the longest matching prefix.
+    pub fn cache_control(&self, path: &str) -> Option<&str> {
+        self.cache_control
+            .iter()
+            .filter(|rule| path.starts_with(&rule.prefix))
+            .max_by_key(|rule| rule.prefix.len())
+            .map(|rule| rule.value.as_str())
+    }
+}
+impl UploadConfig {
+    /// Whether a file of `media_type` may be shown in the browser. Parameters such as `charset` dont matter.
+    pub fn is_inline(&self, media_type: &str) -> bool {
+        let essence = media_type.split(';').next().unwrap_or_default().trim();
+        self.inline_types
+            .iter()
+            .any(|inline| inline.eq_ignore_ascii_case(essence))
+    }
+}
+impl GcConfig {
+    pub fn lifetime(&self) -> Duration {
+        Duration::from_secs(self.lifetime_secs)
+    }
+    pub fn max_lifetime(&self) -> Duration {
+        Duration::from_secs(self.max_lifetime_secs)
+    }
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+    pub fn abandoned(&self) -> Duration {
+        Duration::from_secs(self.abandoned_secs)
+    }
+}
+impl S3Config {
+    /// The smallest part S3 accepts, other than the last.
+    const MIN_PART_SIZE: usize = 5 * 1024 * 1024;
+    fn validate(&mut self) -> io::Result<()> {
+        let host = self
+            .endpoint
+            .strip_prefix("http://")
+            .ok_or_else(|| invalid("storage.s3.endpoint must be an \"http://\" URL".to_owned()))?;
+        if host.trim_end_matches('/').is_empty() || host.trim_end_matches('/').contains('/') {
+            return Err(invalid(
+                "storage.s3.endpoint must be just a host and port, e.g. \"http://127.0.0.1:9000\""
+                    .to_owned(),
+            ));
+        }
+        if self.bucket.is_empty() {
+            return Err(invalid("storage.s3.bucket must be set".to_owned()));
+        }
+        if self.access_key.is_empty() {
+            self.access_key = std::env::var("AWS_ACCESS_KEY_ID").unwrap_or_default();
+        }
+        if self.secret_key.is_empty() {
+            self.secret_key = std::env::var("AWS_SECRET_ACCESS_KEY").unwrap_or_default();
+        }
+        if self.access_key.is_empty() || self.secret_key.is_empty() {
+            return Err(invalid(
+                "storage.s3.access_key and storage.s3.secret_key must be set, or AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY"
+                    .to_owned(),
+            ));
+        }
+        if self.part_size < Self::MIN_PART_SIZE {
+            return Err(invalid(format!(
+                "storage.s3.part_size must be at least {}",
+                Self::MIN_PART_SIZE
+            )));
+        }
+        if self.timeout_ms == 0 {
+            return Err(invalid("Timeouts must be at least 1ms".to_owned()));
+        }
+        Ok(())
+    }
+    /// The `host:port` part of the endpoint.
+    pub fn host(&self) -> &str {
+        self.endpoint
+            .strip_prefix("http://")
+            .unwrap_or(&self.endpoint)
+            .trim_end_matches('/')
+    }
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+/// Pairs each flag with its value, accepting both `--flag value` and `--flag=value`. Flags which dont take a value are paired with `None`.
+fn split_flags(args: impl IntoIterator<Item = String>) -> Vec<(String, Option<String>)> {
+    const SWITCHES: [&str; 8] = [
+        "--evented",
+        "evented",
+        "--threaded",
+        "--gc",
+        "gc",
+        "--no-gc",
+        "--dedup",
+        "--encrypt",
+    ];
+    let mut args = args.into_iter();
+    let mut flags = Vec::new();
+    while let Some(arg) = args.next() {
+        if let Some((flag, value)) = arg.split_once('=').filter(|_| arg.starts_with("--")) {
+            flags.push((flag.to_owned(), Some(value.to_owned())));
+        } else if SWITCHES.contains(&arg.as_str()) {
+            flags.push((arg, None));
+        } else {
+            let value = args.next();
+            flags.push((arg, value));
+        }
+    }
+    flags
+}
+fn parse<T: FromStr>(flag: &str, value: &str) -> io::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|err| invalid(format!("Invalid value \"{value}\" for {flag}: {err}")))
+}
+fn canonical_dir(setting: &str, path: &Path) -> io::Result<PathBuf> {
+    path.canonicalize()
+        .ok()
+        .filter(|path| path.is_dir())
+        .ok_or_else(|| {
+            invalid(format!(
+                "{setting} \"{}\" is not a directory, in {}",
+                path.display(),
+                std::env::current_dir()
+                    .map(|dir| dir.display().to_string())
+                    .unwrap_or_default()
+            ))
+        })
+}
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A temporary directory holding `site` and `files` folders, for configs which need to pass validation.
+    fn dirs() -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("poc_project-config-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("site")).unwrap();
+        std::fs::create_dir_all(dir.join("files")).unwrap();
+        dir
+    }
+    fn valid() -> Config {
+        let dir = dirs();
+        Config {
+            site_path: dir.join("site"),
+            files_path: dir.join("files"),
+            ..Config::default()
+        }
+    }
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| (*arg).to_owned()).collect()
+    }
+
+    #[test]
+    fn config_files_are_parsed() {
+        let config: Config = toml::from_str(
+            "workers = 4\n\
+             evented = true\n\
+             [gc]\n\
+             lifetime_secs = 60\n\
+             [uploads]\n\
+             max_size = 1024\n\
+             inline_types = [\"image/png\"]\n\
+             [storage]\n\
+             backend = \"memory\"\n\
+             [[cache_control]]\n\
+             prefix = \"/static/\"\n\
+             value = \"max-age=60\"\n",
+        )
+        .unwrap();
+        assert_eq!(config.workers, 4);
+        assert!(config.evented);
+        assert_eq!(config.gc.lifetime_secs, 60);
+        assert_eq!(
+            config.gc.max_lifetime_secs,
+            GcConfig::default().max_lifetime_secs
+        );
+        assert_eq!(config.uploads.max_size, 1024);
+        assert!(config.uploads.is_inline("image/png"));
+        assert!(!config.uploads.is_inline("text/html"));
+        assert_eq!(config.storage.backend, StorageBackend::Memory);
+        assert_eq!(config.cache_control("/static/app.js"), Some("max-age=60"));
+        assert_eq!(config.queue_size, Config::default().queue_size);
+        // Misspelt settings are refused rather than ignored
+        for text in [
+            "wrokers = 4",
+            "[gc]\nenable = true",
+            "[storage]\nbackend = \"disk\"",
+        ] {
+            assert!(toml::from_str::<Config>(text).is_err(), "{text}");
+        }
+    }
+
+    #[test]
+    fn bad_settings_are_refused() {
+        let mut config = valid();
+        config.validate().unwrap();
+        assert!(config.site_path.is_absolute());
+        let cases: [fn(&mut Config); 9] = [
+            |config| config.workers = 0,
+            |config| config.timeout_ms = 0,
+            |config| config.gc.lifetime_secs = config.gc.max_lifetime_secs + 1,
+            |config| config.uploads.id_length = 0,
+            |config| config.uploads.id_alphabet = "aab".to_owned(),
+            |config| config.uploads.id_alphabet = "ab/".to_owned(),
+            |config| config.uploads.inline_types = vec!["text".to_owned()],
+            |config| config.cache_control[0].prefix = "static/".to_owned(),
+            |config| config.files_path = PathBuf::from("/nonexistent/files"),
+        ];
+        for (i, break_config) in cases.into_iter().enumerate() {
+            let mut config = valid();
+            break_config(&mut config);
+            let err = config.validate().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput, "case {i}: {err}");
+        }
+        let mut config = valid();
+        config.storage.backend = StorageBackend::S3;
+        config.storage.s3.access_key = "key".to_owned();
+        config.storage.s3.secret_key = "secret".to_owned();
+        assert!(config
+            .validate()
+            .unwrap_err()
+            .to_string()
+            .contains("bucket"));
+    }
+
+    #[test]
+    fn flags_are_split() {
+        assert_eq!(
+            split_flags(args(&[
+                "--workers=4",
+                "--gc",
+                "--address",
+                "127.0.0.1:8080",
+                "evented",
+                "--id-alphabet=a=b",
+                "--bogus",
+            ])),
+            vec![
+                ("--workers".to_owned(), Some("4".to_owned())),
+                ("--gc".to_owned(), None),
+                ("--address".to_owned(), Some("127.0.0.1:8080".to_owned())),
+                ("evented".to_owned(), None),
+                ("--id-alphabet".to_owned(), Some("a=b".to_owned())),
+                ("--bogus".to_owned(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let dir = dirs();
+        let path = dir.join("config.toml");
+        std::fs::write(
+            &path,
+            format!(
+                "workers = 4\ntimeout_ms = 100\nsite_path = {:?}\nfiles_path = {:?}\n",
+                dir.join("site"),
+                dir.join("files")
+            ),
+        )
+        .unwrap();
+        let path = path.to_str().unwrap();
+        let config = Config::load(args(&["--config", path, "--workers=8", "--gc"])).unwrap();
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.timeout_ms, 100);
+        assert!(config.gc.enabled);
+        for (args, message) in [
+            (args(&["--config", path, "--bogus"]), "Unknown argument"),
+            (args(&["--config", path, "--workers"]), "needs a value"),
+            (args(&["--config", path, "--workers=many"]), "Invalid value"),
+            (args(&["--config", path, "--workers=0"]), "workers"),
+        ] {
+            let err = Config::load(args).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            assert!(err.to_string().contains(message), "{err}");
+        }
+        let missing = dir.join("missing.toml");
+        let err = Config::load(args(&["--config", missing.to_str().unwrap()])).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
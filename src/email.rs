@@ -19,12 +19,12 @@ pub fn email(mut packet: HttpRequest, address: SocketAddr, name: String) {
                     .expect(&format!("Non-existent inbox: {}", addr.display())),
             )
             .unwrap();
-            let _ = packet.respond_string("HTTP/1.1 200 Ok\r\n\r\n"); // Send header so client is ready to receive file
-            packet.respond_data(&data);
-            packet
-                .body_stream()
-                .shutdown(std::net::Shutdown::Both)
-                .unwrap();
+            let _ = packet.respond_head(
+                "200 Ok",
+                &[("Content-Type", "text/plain; charset=utf-8")],
+                Some(data.len() as u64),
+            ); // Send header so client is ready to receive file
+            let _ = packet.respond_data(&data);
             return;
         };
         let mut html = String::from(
@@ -45,17 +45,16 @@ pub fn email(mut packet: HttpRequest, address: SocketAddr, name: String) {
             ));
         }
         html.push_str("</body></html>");
-        let _ = packet.respond_string("HTTP/1.1 200 Ok\r\n\r\n"); // Send header so client is ready to receive file
-        packet.respond_string(&html);
+        let _ = packet.respond(
+            "200 Ok",
+            &[("Content-Type", "text/html; charset=utf-8")],
+            &html,
+        );
         packet.read_all();
         log!("{packet}\n");
     } else {
-        let _ = packet.respond_string("HTTP/1.1 401 Ok\r\nWWW-Authenticate: Basic\r\n\r\n");
+        let _ = packet.respond("401 Unauthorized", &[("WWW-Authenticate", "Basic")], "");
         packet.read_all();
         log!("{packet}\n");
     }
-    packet
-        .body_stream()
-        .shutdown(std::net::Shutdown::Both)
-        .unwrap();
 }
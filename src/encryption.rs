@@ -0,0 +1,369 @@
+use std::io::{self, Read, Seek, SeekFrom, Write};
+
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    ChaCha20,
+};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::storage::{FileWriter, StoredFile};
+
+/// How much of a file is encrypted at a time. Every chunk is followed by its tag, so a chunk can be decrypted without reading the rest of the file.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// The length of the tag ChaCha20-Poly1305 adds to each chunk.
+const TAG_SIZE: usize = 16;
+const SEALED_CHUNK_SIZE: u64 = (CHUNK_SIZE + TAG_SIZE) as u64;
+
+/// The key an upload is encrypted with, derived from a secret only the upload's link holds. The server keeps [`UploadKey::check`] so it can refuse a wrong secret, but cant work out the key from it.
+pub struct UploadKey {
+    cipher: ChaCha20Poly1305,
+    /// A separate key for the chunks of a resumable upload, see [`PartialEncryption`].
+    partial_key: [u8; 32],
+    check: String,
+}
+impl UploadKey {
+    pub fn from_secret(secret: &str) -> Self {
+        let key = hmac(secret.as_bytes(), b"poc_project upload key");
+        let check = hmac(secret.as_bytes(), b"poc_project upload key check")
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let partial_key = hmac(secret.as_bytes(), b"poc_project partial upload key");
+        Self {
+            cipher: ChaCha20Poly1305::new_from_slice(&key)
+                .expect("HMAC-SHA256 gives a 32 byte key"),
+            partial_key: partial_key
+                .try_into()
+                .expect("HMAC-SHA256 gives a 32 byte key"),
+            check,
+        }
+    }
+    /// A value to store alongside the upload, which only this key's secret gives.
+    pub fn check(&self) -> &str {
+        &self.check
+    }
+}
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+/// The nonce for a chunk, which is its number and whether it is the last, so chunks cant be reordered and a file cant be cut short without being noticed.
+fn nonce(chunk: u64, last: bool) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&chunk.to_be_bytes());
+    nonce[11] = u8::from(last);
+    nonce
+}
+/// Encrypts a file a chunk at a time as it is written. The last chunk is only written by [`FileWriter::finish`], so a file which isnt finished cant be decrypted.
+pub struct EncryptingWriter {
+    inner: Box<dyn FileWriter>,
+    cipher: ChaCha20Poly1305,
+    buffer: Vec<u8>,
+    chunk: u64,
+}
+impl EncryptingWriter {
+    pub fn new(inner: Box<dyn FileWriter>, key: &UploadKey) -> Self {
+        Self {
+            inner,
+            cipher: key.cipher.clone(),
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+            chunk: 0,
+        }
+    }
+    fn write_chunk(&mut self, last: bool) -> io::Result<()> {
+        let sealed = self
+            .cipher
+            .encrypt(&nonce(self.chunk, last).into(), self.buffer.as_slice())
+            .map_err(|_| io::Error::other("Failed to encrypt chunk"))?;
+        self.inner.write_all(&sealed)?;
+        self.buffer.clear();
+        self.chunk += 1;
+        Ok(())
+    }
+}
+impl Write for EncryptingWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full chunk is held until more arrives, as it might be the last
+        if self.buffer.len() == CHUNK_SIZE && !buf.is_empty() {
+            self.write_chunk(false)?;
+        }
+        let written = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..written]);
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl FileWriter for EncryptingWriter {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.write_chunk(true)?;
+        self.inner.finish()
+    }
+}
+/// Decrypts a file written by [`EncryptingWriter`] as it is read, a chunk at a time.
+pub struct DecryptingFile {
+    inner: Box<dyn StoredFile>,
+    cipher: ChaCha20Poly1305,
+    chunks: u64,
+    len: u64,
+    pos: u64,
+    /// The number and contents of the chunk last decrypted.
+    chunk: Option<(u64, Vec<u8>)>,
+}
+impl DecryptingFile {
+    pub fn new(inner: Box<dyn StoredFile>, key: &UploadKey) -> io::Result<Self> {
+        let sealed_len = inner.len();
+        let chunks = sealed_len.div_ceil(SEALED_CHUNK_SIZE);
+        // Every chunk, even an empty last one, has a tag
+        let last_sealed_len = sealed_len - chunks.saturating_sub(1) * SEALED_CHUNK_SIZE;
+        if chunks == 0 || last_sealed_len < TAG_SIZE as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Encrypted file is cut short",
+            ));
+        }
+        let len = sealed_len - chunks * TAG_SIZE as u64;
+        Ok(Self {
+            inner,
+            cipher: key.cipher.clone(),
+            chunks,
+            len,
+            pos: 0,
+            chunk: None,
+        })
+    }
+    /// Reads and decrypts a chunk, failing if it was changed or belongs somewhere else.
+    fn decrypt_chunk(&mut self, chunk: u64) -> io::Result<Vec<u8>> {
+        self.inner
+            .seek(SeekFrom::Start(chunk * SEALED_CHUNK_SIZE))?;
+        let mut sealed = Vec::with_capacity(SEALED_CHUNK_SIZE as usize);
+        (&mut self.inner)
+            .take(SEALED_CHUNK_SIZE)
+            .read_to_end(&mut sealed)?;
+        let last = chunk + 1 == self.chunks;
+        self.cipher
+            .decrypt(&nonce(chunk, last).into(), sealed.as_slice())
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Encrypted chunk {chunk} failed to decrypt"),
+                )
+            })
+    }
+}
+impl Read for DecryptingFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let chunk = self.pos / CHUNK_SIZE as u64;
+        if self
+            .chunk
+            .as_ref()
+            .is_none_or(|(number, _)| *number != chunk)
+        {
+            let plain = self.decrypt_chunk(chunk)?;
+            self.chunk = Some((chunk, plain));
+        }
+        let plain = &self.chunk.as_ref().expect("Decrypted above").1;
+        let offset = (self.pos % CHUNK_SIZE as u64) as usize;
+        let available = plain.get(offset..).unwrap_or_default();
+        if available.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let bytes_read = available.len().min(buf.len());
+        buf[..bytes_read].copy_from_slice(&available[..bytes_read]);
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+impl Seek for DecryptingFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Seek before the start of the file",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+impl StoredFile for DecryptingFile {
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+/// Encrypts what is written to, or decrypts what is read from, the chunks of a resumable upload received so far. A chunk can be cut off at any byte and carried on from there, so every byte is encrypted with the ChaCha20 keystream at its offset, which keeps the data the same size. There is no tag, so this only keeps the chunks secret until the upload is complete and stored with [`EncryptingWriter`].
+pub struct PartialEncryption<T> {
+    inner: T,
+    cipher: ChaCha20,
+    pos: u64,
+}
+impl<T> PartialEncryption<T> {
+    /// Starts at `offset` bytes into the upload, e.g. the end of the data when appending to it.
+    pub fn new(inner: T, key: &UploadKey, offset: u64) -> Self {
+        Self {
+            inner,
+            // Each upload has its own key, and each offset is only ever written once, so the nonce doesnt need to change
+            cipher: ChaCha20::new(&key.partial_key.into(), &[0u8; 12].into()),
+            pos: offset,
+        }
+    }
+    fn apply(&mut self, buf: &mut [u8]) {
+        self.cipher.seek(self.pos);
+        self.cipher.apply_keystream(buf);
+    }
+}
+impl<T: Write> Write for PartialEncryption<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut encrypted = buf.to_vec();
+        self.apply(&mut encrypted);
+        let written = self.inner.write(&encrypted)?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+impl<T: Read> Read for PartialEncryption<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        self.apply(&mut buf[..bytes_read]);
+        self.pos += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Cursor, sync::Arc};
+
+    use super::*;
+    use crate::{
+        metadata::Metadata,
+        storage::{MemoryStorage, Storage},
+    };
+
+    /// Enough for two full chunks and a bit.
+    fn plain() -> Vec<u8> {
+        (0..2 * CHUNK_SIZE + 1000)
+            .map(|i| (i % 251) as u8)
+            .collect()
+    }
+    /// Encrypts `data` with `secret`, returning what is stored.
+    fn seal(data: &[u8], secret: &str) -> Vec<u8> {
+        let storage = MemoryStorage::new();
+        storage.create("a", &Metadata::default()).unwrap();
+        let mut writer = Box::new(EncryptingWriter::new(
+            storage.put("a").unwrap(),
+            &UploadKey::from_secret(secret),
+        ));
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap();
+        let mut sealed = Vec::new();
+        storage.get("a").unwrap().read_to_end(&mut sealed).unwrap();
+        sealed
+    }
+    fn open(sealed: Vec<u8>, secret: &str) -> io::Result<DecryptingFile> {
+        let file: Box<dyn StoredFile> = Box::new(Cursor::new(Arc::<[u8]>::from(sealed)));
+        DecryptingFile::new(file, &UploadKey::from_secret(secret))
+    }
+
+    #[test]
+    fn round_trip() {
+        for data in [plain(), Vec::new(), plain()[..CHUNK_SIZE].to_vec()] {
+            let sealed = seal(&data, "secret");
+            // Every chunk gets a tag, even an empty last one
+            let chunks = data.len().div_ceil(CHUNK_SIZE).max(1);
+            assert_eq!(sealed.len(), data.len() + chunks * TAG_SIZE);
+            assert!(data.is_empty() || sealed[..data.len()] != data);
+            let mut file = open(sealed, "secret").unwrap();
+            assert_eq!(file.len(), data.len() as u64);
+            let mut read = Vec::new();
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(read, data);
+        }
+    }
+
+    #[test]
+    fn seeking() {
+        let data = plain();
+        let mut file = open(seal(&data, "secret"), "secret").unwrap();
+        let start = CHUNK_SIZE as u64 - 5;
+        file.seek(SeekFrom::Start(start)).unwrap();
+        let mut read = [0u8; 10];
+        file.read_exact(&mut read).unwrap();
+        assert_eq!(read, data[start as usize..start as usize + 10]);
+        file.seek(SeekFrom::End(-3)).unwrap();
+        let mut read = Vec::new();
+        file.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data[data.len() - 3..]);
+    }
+
+    #[test]
+    fn wrong_key_is_refused() {
+        assert_ne!(
+            UploadKey::from_secret("secret").check(),
+            UploadKey::from_secret("wrong").check()
+        );
+        let mut file = open(seal(b"hello", "secret"), "wrong").unwrap();
+        let err = file.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn changes_are_noticed() {
+        let mut sealed = seal(&plain(), "secret");
+        // Dropping the last chunk leaves a chunk which wasnt sealed as the last
+        sealed.truncate(2 * SEALED_CHUNK_SIZE as usize);
+        let mut file = open(sealed.clone(), "secret").unwrap();
+        file.seek(SeekFrom::Start(CHUNK_SIZE as u64)).unwrap();
+        let err = file.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        sealed[10] ^= 1;
+        let err = open(sealed, "secret")
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(open(vec![0; TAG_SIZE - 1], "secret").is_err());
+    }
+
+    #[test]
+    fn partial_chunks_carry_on_from_their_offset() {
+        let key = UploadKey::from_secret("secret");
+        let mut stored = Vec::new();
+        PartialEncryption::new(&mut stored, &key, 0)
+            .write_all(b"hello ")
+            .unwrap();
+        PartialEncryption::new(&mut stored, &key, 6)
+            .write_all(b"world")
+            .unwrap();
+        assert_eq!(stored.len(), 11);
+        assert_ne!(stored, b"hello world");
+        let mut read = Vec::new();
+        PartialEncryption::new(stored.as_slice(), &key, 0)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, b"hello world");
+        let mut read = Vec::new();
+        PartialEncryption::new(&stored[6..], &key, 6)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, b"world");
+    }
+}
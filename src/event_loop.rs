@@ -0,0 +1,787 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, Cursor, Read, Seek, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::{fs::OpenOptionsExt, net::UnixStream},
+    },
+    sync::mpsc::{self, Receiver},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    config::Config, handle_request, http_methods, http_request::HttpRequest, log,
+    service_unavailable, thread_pool::ThreadPool, FILES_PATH,
+};
+
+const LISTENER: u64 = 0;
+const WAKER: u64 = 1;
+/// The most connections held open at once, past this new clients are sent a `503 Service Unavailable`.
+const MAX_CONNECTIONS: usize = 10_000;
+/// The longest a client can take to send a request line and headers, no matter how often it sends a byte.
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest request line and headers accepted.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+/// Requests larger than this are spooled to an unnamed file rather than kept in memory.
+const SPOOL_THRESHOLD: usize = 64 * 1024;
+/// The longest chunk size or trailer line followed, longer lines are left for the worker to reject.
+const MAX_LINE_LENGTH: usize = 4096;
+
+/// Accepts connections on the provided address and waits for each request to fully arrive using epoll, only then queueing it for a pool of `workers` threads which run
+/// ```no_run
+/// handle_request()
+/// ```
+/// to respond. Idle and slow clients only cost a buffer rather than a thread. Once `queue_size` requests are waiting for a worker, new requests are sent a `503 Service Unavailable`.
+/// # Errors
+/// Returns an IO error if the TcpListener fails to bind to the requested address, or epoll or the worker threads fail to set up.
+pub fn host_server_evented(
+    address: SocketAddr,
+    workers: usize,
+    queue_size: usize,
+) -> io::Result<()> {
+    serve(TcpListener::bind(address)?, workers, queue_size)
+}
+/// Runs [`host_server_evented`] on a listener which is already bound.
+fn serve(listener: TcpListener, workers: usize, queue_size: usize) -> io::Result<()> {
+    let address = listener.local_addr()?;
+    listener.set_nonblocking(true)?;
+    let epoll = Epoll::new()?;
+    epoll.add(listener.as_raw_fd(), LISTENER)?;
+    let (waker, wake_listener) = UnixStream::pair()?;
+    waker.set_nonblocking(true)?;
+    wake_listener.set_nonblocking(true)?;
+    epoll.add(wake_listener.as_raw_fd(), WAKER)?;
+    let (returns, returned) = mpsc::channel();
+    let pool = ThreadPool::new("ClientHandler", workers, queue_size, {
+        let returns = returns.clone();
+        move |request: BufferedRequest| {
+            let token = request.token;
+            if let Some(client) = handle_buffered(request, address) {
+                if returns.send((token, client)).is_ok() {
+                    let _ = (&waker).write(&[1]);
+                }
+            }
+        }
+    })?;
+    drop(returns);
+    log!("==================== Server running on {address} (evented) ====================");
+    EventLoop {
+        listener,
+        epoll,
+        wake_listener,
+        returned,
+        pool,
+        connections: HashMap::new(),
+        next_token: WAKER + 1,
+    }
+    .run()
+}
+
+/// Handles a request which has been read ahead of time, returning the connection if it is still open.
+fn handle_buffered(request: BufferedRequest, address: SocketAddr) -> Option<TcpStream> {
+    request.client.set_nonblocking(false).ok()?;
+    let client_ip = request.client.peer_addr();
+    let mut packet = HttpRequest::with_buffered(request.client.try_clone().ok()?, request.data);
+    if request.continued {
+        packet.set_continued();
+    }
+    if request.unread_body {
+        packet.close_connection();
+    }
+    match packet.protocol() {
+        Some(protocol) => handle_request(packet, protocol, client_ip.as_ref().ok(), address),
+        None => log!("Client provided no protocol."),
+    }
+    request.client.set_nonblocking(true).ok()?;
+    Some(request.client) // If the request closed the connection, epoll reports it as soon as it is returned
+}
+
+/// A request read off a connection by the event loop, ready for a worker.
+struct BufferedRequest {
+    token: u64,
+    client: TcpStream,
+    data: Option<Box<dyn Read + Send>>,
+    /// Whether the client was sent `100 Continue` before its body was read.
+    continued: bool,
+    /// Whether the body was left unread as it would be refused, so the connection is closed after the reply.
+    unread_body: bool,
+}
+
+struct EventLoop {
+    listener: TcpListener,
+    epoll: Epoll,
+    wake_listener: UnixStream,
+    returned: Receiver<(u64, TcpStream)>,
+    pool: ThreadPool<BufferedRequest>,
+    connections: HashMap<u64, Connection>,
+    next_token: u64,
+}
+impl EventLoop {
+    fn run(mut self) -> io::Result<()> {
+        let mut events = vec![libc::epoll_event { events: 0, u64: 0 }; 1024];
+        loop {
+            let ready = self.epoll.wait(&mut events, Duration::from_secs(1))?;
+            for event in &events[0..ready] {
+                match event.u64 {
+                    LISTENER => self.accept(),
+                    WAKER => self.reclaim(),
+                    token => self.read(token),
+                }
+            }
+            self.expire();
+        }
+    }
+    fn accept(&mut self) {
+        loop {
+            let client = match self.listener.accept() {
+                Ok((client, _)) => client,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return,
+                Err(err) => {
+                    log!("Failed to accept connection: {err}");
+                    return;
+                }
+            };
+            if self.connections.len() >= MAX_CONNECTIONS {
+                log!("Connection limit reached, turning client away.");
+                service_unavailable(client);
+                continue;
+            }
+            let token = self.next_token;
+            self.next_token += 1;
+            let timeout = Config::get().timeout();
+            if client.set_read_timeout(Some(timeout)).is_err()
+                || client.set_write_timeout(Some(timeout)).is_err()
+                || client.set_nonblocking(true).is_err()
+                || self.epoll.add(client.as_raw_fd(), token).is_err()
+            {
+                log!("Failed to set up connection");
+                continue;
+            }
+            self.connections.insert(token, Connection::new(client));
+        }
+    }
+    /// Takes back connections a worker has finished with, and starts on any request already buffered for them.
+    fn reclaim(&mut self) {
+        let _ = self.wake_listener.read(&mut [0u8; 1024]);
+        while let Ok((token, client)) = self.returned.try_recv() {
+            let Some(connection) = self.connections.get_mut(&token) else {
+                continue;
+            };
+            connection.client = Some(client);
+            connection.handled += 1;
+            connection.last_active = Instant::now();
+            if let Some(client) = connection.client.as_ref() {
+                if self.epoll.add(client.as_raw_fd(), token).is_err() {
+                    self.connections.remove(&token);
+                    continue;
+                }
+            }
+            self.process(token);
+            if self.connections.get(&token).is_some_and(|connection| {
+                connection.client.is_some() && connection.closed_by_client
+            }) {
+                self.close(token);
+            }
+        }
+    }
+    /// Reads whatever the client has sent so far, then checks if a request is complete.
+    fn read(&mut self, token: u64) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let Some(client) = connection.client.as_mut() else {
+            return;
+        };
+        let mut buf = [0u8; 16 * 1024];
+        loop {
+            match client.read(&mut buf) {
+                Ok(0) => {
+                    // The client may have sent a whole request before closing its end, so answer it first
+                    connection.closed_by_client = true;
+                    break;
+                }
+                Ok(bytes_read) => {
+                    connection.last_active = Instant::now();
+                    connection.pending.extend_from_slice(&buf[0..bytes_read]);
+                    if connection.pending.len() > SPOOL_THRESHOLD {
+                        break; // Give it a chance to be spooled
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(_) => {
+                    self.close(token);
+                    return;
+                }
+            }
+        }
+        self.process(token);
+        if self
+            .connections
+            .get(&token)
+            .is_some_and(|connection| connection.client.is_some() && connection.closed_by_client)
+        {
+            self.close(token);
+        }
+    }
+    /// Moves buffered bytes into the request being parsed, dispatching it to a worker once it is complete.
+    fn process(&mut self, token: u64) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        if connection.client.is_none() {
+            return; // A worker has it
+        }
+        match connection.advance() {
+            Ok(Progress::Waiting) => {}
+            Ok(Progress::Ready {
+                data,
+                continued,
+                unread_body,
+            }) => self.dispatch(token, data, continued, unread_body),
+            Ok(Progress::HeadTooLarge) => {
+                log!("Request head too large, closing connection.");
+                if let Some(client) = connection.client.as_mut() {
+                    let _ = client.write_all(b"HTTP/1.1 431 Request Header Fields Too Large\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                }
+                self.close(token);
+            }
+            Ok(Progress::BodyTooLarge) => {
+                let max_size = Config::get().uploads.max_size;
+                log!("Request body larger than {max_size} bytes, closing connection.");
+                let body = format!("Uploads cannot be larger than {max_size} bytes.\r\n");
+                if let Some(client) = connection.client.as_mut() {
+                    let _ = client.write_all(format!("HTTP/1.1 413 Payload Too Large\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len()).as_bytes());
+                }
+                self.close(token);
+            }
+            Err(err) => {
+                log!("Failed to buffer request: {err}");
+                self.close(token);
+            }
+        }
+    }
+    fn dispatch(
+        &mut self,
+        token: u64,
+        data: Box<dyn Read + Send>,
+        continued: bool,
+        unread_body: bool,
+    ) {
+        let Some(connection) = self.connections.get_mut(&token) else {
+            return;
+        };
+        let Some(client) = connection.client.take() else {
+            return;
+        };
+        let _ = self.epoll.delete(client.as_raw_fd());
+        log!(
+            "Request ready, {}/{} worker(s) busy, {} request(s) queued, {} connection(s) open.",
+            self.pool.busy(),
+            self.pool.workers(),
+            self.pool.queued(),
+            self.connections.len()
+        );
+        let request = BufferedRequest {
+            token,
+            client,
+            data: Some(data),
+            continued,
+            unread_body,
+        };
+        if let Err(request) = self.pool.submit(request) {
+            log!("Worker pool saturated, turning client away.");
+            self.connections.remove(&token);
+            let _ = request.client.set_nonblocking(false);
+            service_unavailable(request.client);
+        }
+    }
+    /// Closes connections which have been idle, or have taken too long to send a request.
+    fn expire(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<u64> = self
+            .connections
+            .iter()
+            .filter(|(_, connection)| connection.client.is_some() && connection.deadline() < now)
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            self.close(token);
+        }
+    }
+    fn close(&mut self, token: u64) {
+        if let Some(connection) = self.connections.remove(&token) {
+            if let Some(client) = connection.client {
+                let _ = self.epoll.delete(client.as_raw_fd());
+                let _ = client.shutdown(std::net::Shutdown::Both);
+            }
+        }
+    }
+}
+
+enum Progress {
+    Waiting,
+    Ready {
+        data: Box<dyn Read + Send>,
+        continued: bool,
+        unread_body: bool,
+    },
+    HeadTooLarge,
+    /// The body is, or has said it will be, larger than `uploads.max_size`.
+    BodyTooLarge,
+}
+/// Where the parser is within the current request.
+enum Stage {
+    Head,
+    Fixed(u64),
+    Chunked(ChunkScan),
+}
+/// Finds the end of a chunked body without decoding it, the worker does that.
+enum ChunkScan {
+    SizeLine(Vec<u8>),
+    Data(u64),
+    DataEnd(Vec<u8>),
+    TrailerLine(Vec<u8>),
+}
+
+struct Connection {
+    /// `None` while a worker is handling a request.
+    client: Option<TcpStream>,
+    /// Bytes read off the connection but not yet parsed.
+    pending: Vec<u8>,
+    /// The part of the current request parsed so far.
+    request: Vec<u8>,
+    spool: Option<File>,
+    stage: Stage,
+    /// The size of the current request's body, as far as it has been announced.
+    body_size: u64,
+    /// Whether the client has been sent `100 Continue` for the current request.
+    continued: bool,
+    /// Whether the current request's body is left unread, as the worker would refuse it.
+    unread_body: bool,
+    handled: usize,
+    /// Whether the client has shut down its end, so no more requests can arrive.
+    closed_by_client: bool,
+    started: Option<Instant>,
+    last_active: Instant,
+}
+impl Connection {
+    fn new(client: TcpStream) -> Self {
+        Self {
+            client: Some(client),
+            pending: Vec::new(),
+            request: Vec::new(),
+            spool: None,
+            stage: Stage::Head,
+            body_size: 0,
+            continued: false,
+            unread_body: false,
+            handled: 0,
+            closed_by_client: false,
+            started: None,
+            last_active: Instant::now(),
+        }
+    }
+    fn deadline(&self) -> Instant {
+        let idle = if self.handled > 0 && self.started.is_none() {
+            Config::get().keep_alive_timeout()
+        } else {
+            Config::get().timeout()
+        };
+        let deadline = self.last_active + idle;
+        match (&self.stage, self.started) {
+            (Stage::Head, Some(started)) => deadline.min(started + HEAD_TIMEOUT),
+            _ => deadline,
+        }
+    }
+    /// Parses as far through the pending bytes as possible. Anything which cant be understood here is passed on to the worker as-is, which rejects it properly, except bodies larger than any upload, which are refused before they fill the disk, and bodies the worker wouldnt read, which arent asked for or waited on.
+    fn advance(&mut self) -> io::Result<Progress> {
+        if !self.pending.is_empty() && self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+        loop {
+            match &mut self.stage {
+                Stage::Head => {
+                    let scan_from = self.request.len().saturating_sub(3);
+                    self.request.append(&mut self.pending);
+                    let Some(end) = find(&self.request[scan_from..], b"\r\n\r\n") else {
+                        if self.request.len() > MAX_HEAD_SIZE {
+                            return Ok(Progress::HeadTooLarge);
+                        }
+                        return Ok(Progress::Waiting);
+                    };
+                    let end = scan_from + end + 4;
+                    self.pending = self.request.split_off(end);
+                    self.stage = match framing(&self.request) {
+                        Some(stage) => stage,
+                        None => return self.finish(), // Let the worker reject the framing
+                    };
+                    if let Stage::Fixed(length) = self.stage {
+                        self.body_size = length;
+                    }
+                    if self.body_size > Config::get().uploads.max_size {
+                        return Ok(Progress::BodyTooLarge);
+                    }
+                    let has_body = !matches!(self.stage, Stage::Fixed(0));
+                    if has_body
+                        && !http_methods::takes_body(&mut HttpRequest::from_head(
+                            self.request.clone(),
+                        ))
+                    {
+                        // Dont ask for or spool a body which would be thrown away. What has arrived of it cant be told apart from a next request, so the connection closes after the reply
+                        self.pending.clear();
+                        self.unread_body = true;
+                        return self.finish();
+                    }
+                    if has_body && expects_continue(&self.request) {
+                        if let Some(client) = self.client.as_mut() {
+                            client.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                            self.continued = true;
+                        }
+                    }
+                }
+                Stage::Fixed(remaining) => {
+                    let take = (*remaining).min(self.pending.len() as u64) as usize;
+                    *remaining -= take as u64;
+                    let done = *remaining == 0;
+                    let rest = self.pending.split_off(take);
+                    let body = std::mem::replace(&mut self.pending, rest);
+                    self.append(&body)?;
+                    if done {
+                        return self.finish();
+                    }
+                    return Ok(Progress::Waiting);
+                }
+                Stage::Chunked(scan) => {
+                    let mut consumed = 0;
+                    let mut done = false;
+                    let mut malformed = false;
+                    while consumed < self.pending.len() && !done && !malformed {
+                        match scan {
+                            ChunkScan::Data(remaining) => {
+                                let take = (*remaining).min((self.pending.len() - consumed) as u64)
+                                    as usize;
+                                consumed += take;
+                                *remaining -= take as u64;
+                                if *remaining == 0 {
+                                    *scan = ChunkScan::DataEnd(Vec::new());
+                                }
+                            }
+                            ChunkScan::SizeLine(line)
+                            | ChunkScan::DataEnd(line)
+                            | ChunkScan::TrailerLine(line) => {
+                                line.push(self.pending[consumed]);
+                                consumed += 1;
+                                if line.len() > MAX_LINE_LENGTH {
+                                    malformed = true;
+                                } else if line.ends_with(b"\r\n") {
+                                    let text = String::from_utf8_lossy(&line[0..line.len() - 2])
+                                        .into_owned();
+                                    match scan {
+                                        ChunkScan::SizeLine(_) => {
+                                            let size = text.split(';').next().unwrap_or("").trim();
+                                            match u64::from_str_radix(size, 16) {
+                                                Ok(0) => *scan = ChunkScan::TrailerLine(Vec::new()),
+                                                Ok(size) => {
+                                                    self.body_size =
+                                                        self.body_size.saturating_add(size);
+                                                    *scan = ChunkScan::Data(size);
+                                                }
+                                                Err(_) => malformed = true,
+                                            }
+                                        }
+                                        ChunkScan::DataEnd(_) if text.is_empty() => {
+                                            *scan = ChunkScan::SizeLine(Vec::new());
+                                        }
+                                        ChunkScan::TrailerLine(_) if text.is_empty() => done = true,
+                                        ChunkScan::TrailerLine(_) => {
+                                            *scan = ChunkScan::TrailerLine(Vec::new());
+                                        }
+                                        _ => malformed = true,
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    if self.body_size > Config::get().uploads.max_size {
+                        return Ok(Progress::BodyTooLarge);
+                    }
+                    let rest = self.pending.split_off(consumed);
+                    let body = std::mem::replace(&mut self.pending, rest);
+                    self.append(&body)?;
+                    if done || malformed {
+                        return self.finish();
+                    }
+                    return Ok(Progress::Waiting);
+                }
+            }
+        }
+    }
+    /// Adds body bytes to the current request, moving it to a spool file once it gets large.
+    fn append(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if let Some(spool) = self.spool.as_mut() {
+            return spool.write_all(bytes);
+        }
+        self.request.extend_from_slice(bytes);
+        if self.request.len() > SPOOL_THRESHOLD {
+            // O_TMPFILE makes a file with no name, which is deleted as soon as it is closed
+            let mut spool = File::options()
+                .read(true)
+                .write(true)
+                .custom_flags(libc::O_TMPFILE)
+                .open(FILES_PATH.as_path())?;
+            spool.write_all(&self.request)?;
+            self.request.clear();
+            self.spool = Some(spool);
+        }
+        Ok(())
+    }
+    /// Hands over the complete request, leaving any pipelined bytes for the next one.
+    fn finish(&mut self) -> io::Result<Progress> {
+        self.stage = Stage::Head;
+        self.started = None;
+        self.body_size = 0;
+        let request = std::mem::take(&mut self.request);
+        Ok(Progress::Ready {
+            data: match self.spool.take() {
+                Some(mut spool) => {
+                    spool.rewind()?;
+                    Box::new(spool)
+                }
+                None => Box::new(Cursor::new(request)),
+            },
+            continued: std::mem::take(&mut self.continued),
+            unread_body: std::mem::take(&mut self.unread_body),
+        })
+    }
+}
+/// Works out how the body of a request is framed from its head. Returns `None` if the framing is invalid.
+fn framing(head: &[u8]) -> Option<Stage> {
+    let head = String::from_utf8_lossy(head);
+    let mut chunked = false;
+    let mut length = None;
+    for line in head.split("\r\n").skip(1) {
+        if let Some((header, value)) = line.split_once(':') {
+            if header.trim().eq_ignore_ascii_case("Transfer-Encoding") {
+                if !value.trim().eq_ignore_ascii_case("chunked") {
+                    return None;
+                }
+                chunked = true;
+            } else if header.trim().eq_ignore_ascii_case("Content-Length") {
+                for value in value.split(',') {
+                    let value = value.trim().parse::<u64>().ok()?;
+                    if length.is_some_and(|length| length != value) {
+                        return None;
+                    }
+                    length = Some(value);
+                }
+            }
+        }
+    }
+    if chunked && length.is_some() {
+        return None;
+    }
+    Some(if chunked {
+        Stage::Chunked(ChunkScan::SizeLine(Vec::new()))
+    } else {
+        match length {
+            Some(0) | None => Stage::Fixed(0),
+            Some(length) => Stage::Fixed(length),
+        }
+    })
+}
+/// Whether an HTTP/1.1 request is waiting to be told to send its body. Older clients dont know about `100 Continue`, so arent sent it.
+fn expects_continue(head: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+    let is_http_1_1 = lines
+        .next()
+        .is_some_and(|request_line| request_line.ends_with(" HTTP/1.1"));
+    is_http_1_1
+        && lines.any(|line| {
+            line.split_once(':').is_some_and(|(header, value)| {
+                header.trim().eq_ignore_ascii_case("Expect")
+                    && value.trim().eq_ignore_ascii_case("100-continue")
+            })
+        })
+}
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// A minimal level-triggered wrapper around an epoll instance.
+struct Epoll {
+    fd: RawFd,
+}
+impl Epoll {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self { fd })
+    }
+    fn add(&self, fd: RawFd, token: u64) -> io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: (libc::EPOLLIN | libc::EPOLLRDHUP) as u32,
+            u64: token,
+        };
+        if unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_ADD, fd, &mut event) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    fn delete(&self, fd: RawFd) -> io::Result<()> {
+        if unsafe { libc::epoll_ctl(self.fd, libc::EPOLL_CTL_DEL, fd, std::ptr::null_mut()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+    fn wait(&self, events: &mut [libc::epoll_event], timeout: Duration) -> io::Result<usize> {
+        let ready = unsafe {
+            libc::epoll_wait(
+                self.fd,
+                events.as_mut_ptr(),
+                events.len() as i32,
+                timeout.as_millis() as i32,
+            )
+        };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                return Ok(0);
+            }
+            return Err(err);
+        }
+        Ok(ready as usize)
+    }
+}
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{closed, setup, Response};
+
+    /// Runs an event loop on a free port, returning a connection to it.
+    fn connect() -> TcpStream {
+        setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || serve(listener, 2, 4));
+        let client = TcpStream::connect(address).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+    }
+
+    #[test]
+    fn framing_matches_the_worker() {
+        let fixed = |head: &str| match framing(head.as_bytes()) {
+            Some(Stage::Fixed(length)) => Some(length),
+            _ => None,
+        };
+        assert_eq!(
+            fixed("PUT / HTTP/1.1\r\nContent-Length: 5\r\n\r\n"),
+            Some(5)
+        );
+        assert_eq!(fixed("PUT / HTTP/1.1\r\nContent-Length:5\r\n\r\n"), Some(5));
+        assert_eq!(
+            fixed("PUT / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\n"),
+            Some(5)
+        );
+        assert_eq!(fixed("GET / HTTP/1.1\r\n\r\n"), Some(0));
+        assert!(matches!(
+            framing(b"PUT / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"),
+            Some(Stage::Chunked(_))
+        ));
+        // Anything the worker would refuse is left for it to answer
+        for head in [
+            "PUT / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n",
+            "PUT / HTTP/1.1\r\nContent-Length: 5, 6\r\n\r\n",
+            "PUT / HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n",
+            "PUT / HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n",
+        ] {
+            assert!(framing(head.as_bytes()).is_none(), "{head}");
+        }
+    }
+
+    #[test]
+    fn slow_clients_are_waited_for() {
+        let mut client = connect();
+        let request =
+            b"PUT /slow.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 11\r\n\r\nhello world";
+        for piece in request.chunks(5) {
+            client.write_all(piece).unwrap();
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        let response = Response::read(&mut client).unwrap();
+        assert_eq!(response.status, 200);
+        assert!(String::from_utf8_lossy(&response.body).contains("/slow.txt"));
+    }
+
+    #[test]
+    fn oversized_bodies_are_refused_before_they_arrive() {
+        let mut client = connect();
+        let too_large = Config::get().uploads.max_size + 1;
+        client
+            .write_all(format!("PUT /big.txt HTTP/1.1\r\nContent-Length: {too_large}\r\nExpect: 100-continue\r\n\r\n").as_bytes())
+            .unwrap();
+        let response = Response::read(&mut client).unwrap();
+        assert_eq!(response.status, 413);
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert!(closed(&mut client));
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let mut client = connect();
+        client
+            .write_all(b"GET /missing HTTP/1.1\r\n\r\nPUT /one.txt HTTP/1.1\r\nContent-Length: 3\r\n\r\noneGET /missing HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .unwrap();
+        assert_eq!(Response::read(&mut client).unwrap().status, 410);
+        let response = Response::read(&mut client).unwrap();
+        assert_eq!(response.status, 200);
+        assert!(String::from_utf8_lossy(&response.body).contains("/one.txt"));
+        assert_eq!(Response::read(&mut client).unwrap().status, 410);
+        assert!(closed(&mut client));
+    }
+
+    #[test]
+    fn refused_requests_arent_sent_continue() {
+        for (head, status) in [
+            ("PUT /../escape.txt HTTP/1.1", 403),
+            ("POST / HTTP/1.1\r\nContent-Type: text/plain", 415),
+            ("PATCH /resumable/missing HTTP/1.1\r\nContent-Type: application/offset+octet-stream", 404),
+            ("GET /missing HTTP/1.1", 410),
+        ] {
+            let mut client = connect();
+            client
+                .write_all(format!("{head}\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n").as_bytes())
+                .unwrap();
+            let response = Response::read(&mut client).unwrap();
+            assert_eq!(response.status, status, "{head}");
+            assert_eq!(response.header("Connection"), Some("close"), "{head}");
+            assert!(closed(&mut client), "{head}");
+        }
+        let mut client = connect();
+        client
+            .write_all(
+                b"PUT /fine.txt HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+            )
+            .unwrap();
+        let mut interim = [0u8; 25];
+        client.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        client.write_all(b"hello").unwrap();
+        assert_eq!(Response::read(&mut client).unwrap().status, 200);
+    }
+}
@@ -1,123 +1,1209 @@
-use core::panic;
 use std::{
-    collections::hash_map::DefaultHasher,
-    hash::{Hash, Hasher},
-    io::{self, Read, Write},
+    fmt,
+    io::{self, Read, SeekFrom, Write},
     net::SocketAddr,
-    path::{Component, PathBuf},
+    path::{Component, Path},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{email, http_request::HttpRequest, log, FILES_PATH, ROOT_PATH, SITE_PATH};
-/// Hashes the current system time, converts it to hex, makes a file with that name and stores the packet body to that file
-pub fn put(mut packet: HttpRequest, address: SocketAddr) {
-    let host = packet.headers().unwrap().get("Host").unwrap().clone();
-    if let Some(mut name) = packet.path().clone() {
-        let name = &name[1..]; // Remove leading "/"
-        let mut is_100_continue = false;
-        if let Some(headers) = packet.headers() {
-            for (header, value) in headers {
-                if header == "Expect" && value == "100-continue" {
-                    is_100_continue = true;
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{
+    checksum::{self, Expected, HashingWriter},
+    conditional::{self, Precondition},
+    config::Config,
+    email,
+    encryption::{DecryptingFile, EncryptingWriter, PartialEncryption, UploadKey},
+    http_request::{Body, HttpRequest},
+    log,
+    metadata::Metadata,
+    mime,
+    multipart::Multipart,
+    password,
+    range::{self, Byteranges, Ranges},
+    resumable::{PartialUpload, RESUMABLE_PATH},
+    storage::{self, FileWriter, LocalFile, StoredFile, STATIC_DIR},
+    ALLOWED_METHODS, FILES_PATH, ROOT_PATH, SITE_PATH,
+};
+/// How many names to try for an upload's folder before giving up, in case they are already taken.
+const UPLOAD_ID_ATTEMPTS: usize = 16;
+/// The version of the tus resumable upload protocol spoken, sent with every tus response.
+const TUS_VERSION: &str = "1.0.0";
+/// The longest password accepted from an upload form, as the field is read into memory.
+const MAX_PASSWORD_LENGTH: u64 = 1024;
+/// Fills `buf` with random bytes from the OS.
+fn random_bytes(buf: &mut [u8]) -> io::Result<()> {
+    std::fs::File::open("/dev/urandom")?.read_exact(buf)
+}
+/// Reads `bytes` random bytes from the OS and hex encodes them.
+fn random_hex(bytes: usize) -> io::Result<String> {
+    let mut buf = vec![0u8; bytes];
+    random_bytes(&mut buf)?;
+    Ok(buf.iter().map(|byte| format!("{byte:02x}")).collect())
+}
+/// Picks `length` characters uniformly at random from `alphabet`.
+fn random_id(length: usize, alphabet: &[u8]) -> io::Result<String> {
+    // Bytes past the last whole multiple of the alphabet are thrown away, so no character is more likely than another
+    let limit = 256 - 256 % alphabet.len();
+    let mut id = String::with_capacity(length);
+    let mut buf = [0u8; 64];
+    while id.len() < length {
+        random_bytes(&mut buf)?;
+        id.extend(
+            buf.iter()
+                .filter(|byte| usize::from(**byte) < limit)
+                .map(|byte| char::from(alphabet[usize::from(*byte) % alphabet.len()]))
+                .take(length - id.len()),
+        );
+    }
+    Ok(id)
+}
+/// Works out how long an upload's body is from the headers, replying with an error and returning `None` if it cant be accepted. The inner `None` means the body is chunked, so its size isnt known up front.
+fn upload_length(packet: &mut HttpRequest) -> Option<Option<u64>> {
+    let content_length = match packet.body().map(|body| match body {
+        Body::Empty => None,
+        body => Some(body.length()),
+    }) {
+        Ok(Some(length)) => length, // None if the body is chunked
+        Ok(None) => {
+            log!("Request rejected: missing Content-Length");
+            let _ = packet.respond(
+                "411 Length Required",
+                &[],
+                "Uploads must include a \"Content-Length\" header or be chunked.\r\n",
+            );
+            return None;
+        }
+        Err(err) => {
+            log!("Request rejected: {err}");
+            let status = if err.kind() == io::ErrorKind::Unsupported {
+                "501 Not Implemented"
+            } else {
+                "400 Bad Request"
+            };
+            packet.close_connection(); // The body cant be found, so neither can the next request
+            let _ = packet.respond(status, &[], &format!("{err}.\r\n"));
+            return None;
+        }
+    };
+    let max_size = Config::get().uploads.max_size;
+    if let Some(content_length) = content_length.filter(|length| *length > max_size) {
+        log!("Request rejected: {content_length} byte upload exceeds {max_size} bytes");
+        packet.close_connection(); // Dont read the oversized body
+        let _ = packet.respond(
+            "413 Payload Too Large",
+            &[],
+            &format!("Uploads cannot be larger than {max_size} bytes.\r\n"),
+        );
+        return None;
+    }
+    Some(content_length)
+}
+/// Works out how long to keep an upload from the `expires` query parameter or `X-Expires-In` header (in seconds), capped at the configured maximum. Replies with an error and returns `None` if the requested lifetime isnt valid.
+fn upload_lifetime(packet: &mut HttpRequest) -> Option<Duration> {
+    let gc = &Config::get().gc;
+    let requested = match packet.query("expires") {
+        Some(requested) => requested,
+        None => match packet.header("X-Expires-In") {
+            Some(requested) => requested.to_owned(),
+            None => return Some(gc.lifetime()),
+        },
+    };
+    match requested.trim().parse::<u64>() {
+        Ok(secs) if secs > 0 => Some(Duration::from_secs(secs).min(gc.max_lifetime())),
+        _ => {
+            log!("Request rejected: invalid expiry \"{requested}\"");
+            packet.close_connection(); // Dont read the body
+            let _ = packet.respond(
+                "400 Bad Request",
+                &[],
+                "Expiry must be a whole number of seconds, above 0.\r\n",
+            );
+            None
+        }
+    }
+}
+/// Reads the download limit from the `downloads` query parameter or `X-Max-Downloads` header. Replies with an error and returns `None` if the limit isnt valid, the inner `None` means there is no limit.
+fn upload_max_downloads(packet: &mut HttpRequest) -> Option<Option<u64>> {
+    let requested = match packet.query("downloads") {
+        Some(requested) => requested,
+        None => match packet.header("X-Max-Downloads") {
+            Some(requested) => requested.to_owned(),
+            None => return Some(None),
+        },
+    };
+    match requested.trim().parse::<u64>() {
+        Ok(max_downloads) if max_downloads > 0 => Some(Some(max_downloads)),
+        _ => {
+            log!("Request rejected: invalid download limit \"{requested}\"");
+            packet.close_connection(); // Dont read the body
+            let _ = packet.respond(
+                "400 Bad Request",
+                &[],
+                "Download limit must be a whole number, above 0.\r\n",
+            );
+            None
+        }
+    }
+}
+/// Reads the digests the client expects the upload to have. Replies with an error and returns `None` if one is malformed.
+fn upload_digests(packet: &mut HttpRequest) -> Option<Vec<Expected>> {
+    match checksum::expected_digests(packet) {
+        Ok(expected) => Some(expected),
+        Err(message) => {
+            log!("Request rejected: {message}");
+            packet.close_connection(); // Dont read the body
+            let _ = packet.respond("400 Bad Request", &[], &format!("{message}.\r\n"));
+            None
+        }
+    }
+}
+/// Tells the client to go ahead and send the body, if it is waiting to be told.
+fn send_continue(packet: &mut HttpRequest) {
+    if !packet.expects_continue() {
+        return;
+    }
+    packet.set_continued();
+    if packet
+        .respond_string("HTTP/1.1 100 Continue\r\n\r\n")
+        .is_err()
+    {
+        log!("Failed to 100-continue");
+    }
+}
+/// Whether a handler would read the body of a request, going by its method, path and headers alone. The event loop checks this before asking for a body with `100 Continue` or spooling it to disk, so one which would be refused is never sent.
+pub fn takes_body(packet: &mut HttpRequest) -> bool {
+    match packet.method().as_deref() {
+        Some("PUT") => packet.path().is_some_and(|path| {
+            path.strip_prefix('/')
+                .is_some_and(|name| valid_file_name(&path_decode(name)))
+        }),
+        Some("POST") => {
+            packet.header("Upload-Length").is_none() // Starting a resumable upload doesnt take a body
+                && packet
+                    .header("Content-Type")
+                    .and_then(Multipart::<io::Empty>::boundary)
+                    .is_some()
+        }
+        Some("PATCH") => {
+            packet.header("Content-Type") == Some("application/offset+octet-stream")
+                && resumable_token(packet)
+                    .and_then(|token| PartialUpload::open(&token).ok())
+                    .is_some_and(|partial| upload_secret(packet, &partial.metadata).is_ok())
+        }
+        _ => false,
+    }
+}
+/// Checks an uploaded file's name cant escape its folder or overwrite the files kept alongside it.
+fn valid_file_name(name: &str) -> bool {
+    !(name.is_empty()
+        || Path::new(name)
+            .components()
+            .any(|comp| comp == Component::ParentDir)
+        || name.starts_with("/")
+        || name.starts_with("\\")
+        || name.starts_with(".")
+        || name.contains("~")
+        || name.contains("*"))
+}
+/// Stores `metadata` for a new upload under a random id, returning the id. Ids already in use are skipped.
+fn create_upload(metadata: &Metadata) -> io::Result<String> {
+    let uploads = &Config::get().uploads;
+    create_upload_with(metadata, || {
+        random_id(uploads.id_length, uploads.id_alphabet.as_bytes())
+    })
+}
+/// [`create_upload`], trying the ids `next_id` gives.
+fn create_upload_with(
+    metadata: &Metadata,
+    mut next_id: impl FnMut() -> io::Result<String>,
+) -> io::Result<String> {
+    for _ in 0..UPLOAD_ID_ATTEMPTS {
+        let id = next_id()?;
+        if id == RESUMABLE_PATH {
+            continue; // Its links would be taken for a resumable upload's
+        }
+        match storage::get().create(&id, metadata) {
+            Ok(()) => return Ok(id),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                log!("Upload id \"{id}\" already in use, picking another");
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::AlreadyExists,
+        format!("No free upload id after {UPLOAD_ID_ATTEMPTS} attempts"),
+    ))
+}
+/// Copies an upload's body into `file`, counting the bytes that arrive in `received`.
+/// # Errors
+/// Fails if the body is cut short, malformed or larger than the configured maximum. Failing to write to `file` gives an `Other` error.
+fn receive_body(body: &mut impl Read, file: &mut impl Write, received: &mut u64) -> io::Result<()> {
+    loop {
+        let mut buf = [0u8; 1024];
+        match body.read(&mut buf) {
+            Ok(bytes_read) => {
+                if bytes_read == 0 {
+                    return Ok(());
+                }
+                *received += bytes_read as u64;
+                if *received > Config::get().uploads.max_size {
+                    // Only reachable for chunked bodies, as their size isnt known up front
+                    return Err(io::ErrorKind::FileTooLarge.into());
+                }
+                if let Err(err) = file.write_all(&buf[0..bytes_read]) {
+                    return Err(io::Error::other(format!("Failed to write to file: {err}")));
                 }
             }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
         }
-        if is_100_continue
-            && packet
-                .respond_string("HTTP/1.1 100 Continue\r\n\r\n")
-                .is_err()
-        {
-            log!("Failed to 100-continue");
-        }
-
-        if let Ok(now) = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
-            let dir: String = {
-                let mut hasher = DefaultHasher::new();
-                now.as_nanos().hash(&mut hasher);
-                format!("{:0x}", hasher.finish())
-                    .chars()
-                    .cycle()
-                    .take(6)
-                    .collect()
-            }; // Hash system time to create random name, and take first 8 letters of it(looping if required e.g. if the has is 0x53, then there arent enough chars, so becomes 0x53535353)
-            let dir_location = PathBuf::from(FILES_PATH.as_path()).join(&dir);
-            let file_location = dir_location.join(&name); // Make sure the path doesnt include .. for path traversal
-            if file_location
-                .components()
-                .any(|comp| comp == Component::ParentDir)
-                || name.starts_with("/")
-                || name.starts_with("\\")
-                || name.contains("~")
-                || name.contains("*")
-            {
-                log!(
-                    "Request rejected: \"{}/{name}\"",
-                    ROOT_PATH.as_path().display()
-                );
-                packet.respond_string("HTTP/1.1 403 Forbidden\r\n\r\nFile names cannot include \"..\", \"~\", \"*\" or start with \"/\" or \"\\\"\r\n").unwrap();
+    }
+}
+/// Replies to an upload whose body couldnt be received, closing the connection as the rest of the body cant be trusted or read.
+fn reject_body(
+    packet: &mut HttpRequest,
+    err: &io::Error,
+    received: u64,
+    content_length: Option<u64>,
+) {
+    let (status, message) = match err.kind() {
+        io::ErrorKind::FileTooLarge => (
+            "413 Payload Too Large",
+            format!(
+                "Uploads cannot be larger than {} bytes.\r\n",
+                Config::get().uploads.max_size
+            ),
+        ),
+        io::ErrorKind::InvalidData => ("400 Bad Request", format!("Malformed body: {err}.\r\n")),
+        io::ErrorKind::Other => (
+            "500 Internal Server Error",
+            "Failed to store file.\r\n".to_owned(),
+        ),
+        _ => match content_length {
+            Some(content_length) => (
+                "400 Bad Request",
+                format!("Incomplete upload, received {received} of {content_length} bytes.\r\n"),
+            ),
+            None => (
+                "400 Bad Request",
+                format!("Incomplete upload, received {received} bytes before the body ended.\r\n"),
+            ),
+        },
+    };
+    packet.close_connection();
+    let _ = packet.respond(status, &[], &message);
+}
+/// Deletes an upload which couldnt be completed.
+fn discard_upload(id: &str) {
+    if let Err(err) = storage::get().delete(id) {
+        log!("Failed to delete incomplete upload \"{id}\": {err}");
+    }
+}
+/// The metadata for a new upload of `name`, which the caller fills in with anything only known once the body arrives.
+fn new_metadata(
+    packet: &mut HttpRequest,
+    name: &str,
+    lifetime: Duration,
+    max_downloads: Option<u64>,
+) -> Metadata {
+    let mut metadata = Metadata::new(name, lifetime);
+    metadata.uploader_ip = packet
+        .body_stream()
+        .and_then(|tcp| tcp.peer_addr().ok())
+        .map(|addr| addr.ip());
+    metadata.max_downloads = max_downloads;
+    metadata
+}
+/// Makes a token for deleting an upload early. An upload without one still works, it just cant be deleted early.
+fn new_delete_token() -> Option<String> {
+    random_hex(16)
+        .inspect_err(|err| log!("Failed to make delete token: {err}"))
+        .ok()
+}
+/// Makes the secret an upload is encrypted with, if uploads are encrypted, storing only its check in the metadata. The secret is given out in the upload's link and never stored.
+fn new_upload_secret(metadata: &mut Metadata) -> io::Result<Option<String>> {
+    if !Config::get().uploads.encrypt {
+        return Ok(None);
+    }
+    let secret = random_hex(32)?;
+    metadata.key_check = Some(UploadKey::from_secret(&secret).check().to_owned());
+    Ok(Some(secret))
+}
+/// Hashes the password an upload is protected with, if the uploader gave one.
+fn new_password_hash(password: Option<&str>) -> io::Result<Option<String>> {
+    match password.filter(|password| !password.is_empty()) {
+        Some(password) => Ok(Some(password::hash(password, &random_hex(16)?))),
+        None => Ok(None),
+    }
+}
+/// Opens an upload's file for writing, encrypting it if it has a secret.
+fn put_upload(id: &str, secret: Option<&str>) -> io::Result<Box<dyn FileWriter>> {
+    let file = storage::get().put(id)?;
+    Ok(match secret {
+        Some(secret) => Box::new(EncryptingWriter::new(file, &UploadKey::from_secret(secret))),
+        None => file,
+    })
+}
+/// The headers describing an upload, sent in the reply to it.
+fn upload_headers(metadata: &Metadata) -> Vec<(&'static str, String)> {
+    let mut headers = vec![("X-Expires-At", http_date(metadata.expires_at()))];
+    if let Some(max_downloads) = metadata.max_downloads {
+        headers.push(("X-Max-Downloads", max_downloads.to_string()));
+    }
+    if let Some(token) = metadata.delete_token.clone() {
+        headers.push(("X-Delete-Token", token));
+    }
+    if let Some(sha256) = metadata.sha256.clone() {
+        headers.push(("X-Sha256", sha256));
+    }
+    headers
+}
+/// Splits the path of an upload's download link into its id and file name, e.g. `abc123/file.txt`.
+fn upload_target(path: &str) -> Option<(&str, &str)> {
+    path.split_once('/')
+        .filter(|(id, name)| !id.is_empty() && !name.is_empty())
+}
+/// The password sent for an upload, either with HTTP Basic auth (the user name is ignored) or as the `password` query parameter.
+fn upload_password(packet: &mut HttpRequest) -> Option<String> {
+    if let Some(credentials) = packet
+        .header("Authorization")
+        .and_then(|value| value.strip_prefix("Basic "))
+    {
+        let credentials = STANDARD.decode(credentials.trim()).ok()?;
+        let credentials = String::from_utf8(credentials).ok()?;
+        let (_, password) = credentials.split_once(':')?;
+        return Some(password.to_owned());
+    }
+    packet
+        .query("password")
+        .map(|password| percent_decode(&password))
+}
+/// Decodes the `%XX` escapes and `+` signs in a query parameter.
+fn percent_decode(value: &str) -> String {
+    unescape(value, true)
+}
+/// Decodes the `%XX` escapes in a path, where `+` stands for itself.
+fn path_decode(value: &str) -> String {
+    unescape(value, false)
+}
+fn unescape(value: &str, plus_is_space: bool) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        if let Some(byte) = escaped {
+            decoded.push(byte);
+            i += 3;
+        } else {
+            decoded.push(if plus_is_space && bytes[i] == b'+' {
+                b' '
             } else {
-                if std::fs::create_dir(&dir_location).is_ok() {
-                    if let Ok(mut file) = std::fs::OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        .open(&file_location)
-                    {
-                        loop {
-                            let mut buf = [0u8; 1024];
-                            match packet.body_stream().read(&mut buf) {
-                                Ok(bytes_read) => {
-                                    if bytes_read == 0 {
-                                        break;
-                                    }
-                                    if file.write(&buf[0..bytes_read]).is_err() {
-                                        log!(
-                                            "Failed to write byte to file \"{}\"",
-                                            file_location.display()
-                                        );
-                                    }
-                                }
-                                Err(err) => match err.kind() {
-                                    io::ErrorKind::WouldBlock => break,
-                                    err => {
-                                        log!("Stopped writing to file: \"{err}\"");
-                                        return;
-                                    }
-                                },
+                bytes[i]
+            });
+            i += 1;
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+/// Escapes every byte of a path segment other than letters, digits and `-._~`, so names with spaces, `?`, `#` or non-ASCII characters survive in a link.
+fn percent_encode(segment: &str) -> String {
+    segment
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+/// Escapes text to go in HTML, including in a quoted attribute.
+fn html_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+/// The secret an encrypted upload's link holds, from the `key` query parameter or the `X-Upload-Key` header. Returns `None` if the upload isnt encrypted.
+/// # Errors
+/// Returns a `PermissionDenied` error if the secret is missing or wrong.
+fn upload_secret(packet: &mut HttpRequest, metadata: &Metadata) -> io::Result<Option<String>> {
+    let Some(check) = &metadata.key_check else {
+        return Ok(None);
+    };
+    let secret = packet
+        .query("key")
+        .or_else(|| packet.header("X-Upload-Key").map(str::to_owned))
+        .unwrap_or_default();
+    if !constant_time_eq(
+        UploadKey::from_secret(&secret).check().as_bytes(),
+        check.as_bytes(),
+    ) {
+        return Err(io::ErrorKind::PermissionDenied.into());
+    }
+    Ok(Some(secret))
+}
+/// The digest kept for an upload. Encrypted uploads dont keep one, as it would let anyone with the disk check a guess at what they hold.
+fn kept_sha256(digests: &checksum::Digests, secret: Option<&str>) -> Option<String> {
+    secret.is_none().then(|| digests.sha256_hex())
+}
+/// Why `open_upload` refused an upload which needs a password, when it wasnt given or was wrong.
+#[derive(Debug)]
+struct PasswordRequired;
+impl fmt::Display for PasswordRequired {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upload needs a password")
+    }
+}
+impl std::error::Error for PasswordRequired {}
+/// A file opened by `get`, along with the upload's metadata if it is an upload.
+struct Download {
+    file: Box<dyn StoredFile>,
+    metadata: Option<Metadata>,
+    /// The upload's id, which the download is counted against once it is sent.
+    id: Option<String>,
+    modified: SystemTime,
+    /// The file's media type, if it is known without looking inside the file.
+    content_type: Option<String>,
+}
+/// Opens an upload for `get`, refusing uploads which have expired or dont have that file name. The download is counted by [`send_file`], once it knows the file will be sent.
+fn open_upload(packet: &mut HttpRequest, id: &str, name: &str) -> io::Result<Download> {
+    let storage = storage::get();
+    let metadata = storage.stat(id)?;
+    if metadata.is_expired() || metadata.is_used_up() || metadata.file_name != path_decode(name) {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    if metadata.rebuilt {
+        log!("Refusing to serve \"{id}\", as its metadata was lost along with any password or key");
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    // Checked before opening the file, so a wrong password or key never gets to it
+    if let Some(password_hash) = &metadata.password_hash {
+        let matches = upload_password(packet)
+            .and_then(|password| password::rehash(&password, password_hash))
+            .is_some_and(|rehashed| {
+                constant_time_eq(rehashed.as_bytes(), password_hash.as_bytes())
+            });
+        if !matches {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                PasswordRequired,
+            ));
+        }
+    }
+    let secret = upload_secret(packet, &metadata)?;
+    let mut file = storage.get(id)?;
+    if let Some(secret) = secret {
+        file = Box::new(DecryptingFile::new(file, &UploadKey::from_secret(&secret))?);
+    }
+    // What the uploader said it is, unless that says nothing
+    let content_type = metadata
+        .content_type
+        .clone()
+        .filter(|content_type| mime::useful_declared(content_type))
+        .or_else(|| mime::from_name(&metadata.file_name).map(str::to_owned));
+    Ok(Download {
+        file,
+        modified: UNIX_EPOCH + Duration::from_secs(metadata.created),
+        metadata: Some(metadata),
+        id: Some(id.to_owned()),
+        content_type,
+    })
+}
+/// Opens a file inside `root` which isnt an upload, such as a page of the site, refusing paths which escape `root`.
+fn open_static(root: &Path, name: &str) -> io::Result<Download> {
+    let file_location = root.join(name).canonicalize()?; // Fails if the file doesnt exist
+    if !file_location.starts_with(root) || file_location == root {
+        log!("User attempted path traversal");
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    Ok(Download {
+        file: Box::new(LocalFile::open(&file_location)?),
+        metadata: None,
+        id: None,
+        modified: std::fs::metadata(&file_location)?.modified()?,
+        content_type: mime::from_name(name).map(str::to_owned),
+    })
+}
+/// Opens the file a path under the files directory refers to, either an upload or a file in the static folder.
+fn open_files_path(packet: &mut HttpRequest, path: &str) -> io::Result<Download> {
+    if let Some(name) = path
+        .strip_prefix(STATIC_DIR)
+        .and_then(|name| name.strip_prefix('/'))
+    {
+        open_static(&FILES_PATH.join(STATIC_DIR), name)
+    } else if let Some((id, name)) = upload_target(path) {
+        open_upload(packet, id, name)
+    } else {
+        Err(io::ErrorKind::NotFound.into())
+    }
+}
+/// Formats a time as an HTTP date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn http_date(time: SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time)
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string()
+}
+/// The URL an upload can be downloaded from, including the secret it is encrypted with, if any.
+fn upload_url(
+    packet: &mut HttpRequest,
+    address: SocketAddr,
+    dir: &str,
+    name: &str,
+    secret: Option<&str>,
+) -> String {
+    let mut addr = address.to_string();
+    if let Some(host_addr) = packet.header("Host") {
+        addr = host_addr.to_owned();
+        addr.push(':');
+        addr.push_str(&address.port().to_string());
+    }
+    let name = percent_encode(name);
+    let key = secret
+        .map(|secret| format!("?key={secret}"))
+        .unwrap_or_default();
+    if packet.header("Host") == Some("zoe.soutter.com") {
+        format!("http://{}/files/{}/{}{}", addr, dir, name, key)
+    } else {
+        format!("http://{}/{}/{}{}", addr, dir, name, key)
+    }
+}
+/// Makes a folder with a random name and stores the packet body to a file in it
+pub fn put(mut packet: HttpRequest, address: SocketAddr) {
+    if let Some(path) = packet.path() {
+        let Some(name) = path.strip_prefix('/') else {
+            log!("Request rejected: PUT to \"{path}\"");
+            packet.close_connection(); // Dont read the body
+            let _ = packet.respond(
+                "400 Bad Request",
+                &[],
+                "Uploads must be sent to a path starting with \"/\".\r\n",
+            );
+            packet.read_all();
+            log!("{packet}\n");
+            return;
+        };
+        let name = &path_decode(name);
+        let Some(content_length) = upload_length(&mut packet) else {
+            return;
+        };
+        let Some(lifetime) = upload_lifetime(&mut packet) else {
+            return;
+        };
+        let Some(max_downloads) = upload_max_downloads(&mut packet) else {
+            return;
+        };
+        let Some(expected) = upload_digests(&mut packet) else {
+            return;
+        };
+        if !valid_file_name(name) {
+            log!(
+                "Request rejected: \"{}/{name}\"",
+                ROOT_PATH.as_path().display()
+            );
+            packet.close_connection(); // Dont read the body
+            let _ = packet.respond("403 Forbidden", &[], "File names cannot include \"..\", \"~\", \"*\" or start with \".\", \"/\" or \"\\\"\r\n");
+        } else {
+            send_continue(&mut packet);
+            let mut metadata = new_metadata(&mut packet, name, lifetime, max_downloads);
+            metadata.content_type = packet.header("Content-Type").map(str::to_owned);
+            metadata.delete_token = new_delete_token();
+            let password = packet.header("X-Upload-Password").map(str::to_owned);
+            let secret = new_password_hash(password.as_deref()).and_then(|password_hash| {
+                metadata.password_hash = password_hash;
+                new_upload_secret(&mut metadata)
+            });
+            let secret = match secret {
+                Ok(secret) => secret,
+                Err(err) => {
+                    log!("Failed to secure upload: {err}");
+                    packet.close_connection();
+                    let _ = packet.respond(
+                        "500 Internal Server Error",
+                        &[],
+                        "Failed to store file.\r\n",
+                    );
+                    return;
+                }
+            };
+            match create_upload(&metadata) {
+                Ok(id) => {
+                    if let Ok(file) = put_upload(&id, secret.as_deref()) {
+                        let mut received: u64 = 0;
+                        let mut file = HashingWriter::new(file, &expected);
+                        let result = packet
+                            .body()
+                            .and_then(|mut body| receive_body(&mut body, &mut file, &mut received));
+                        let (file, digests) = file.finish();
+                        if result.is_ok() {
+                            if let Some(mismatch) = digests.mismatch(&expected) {
+                                log!(
+                                    "Upload of \"{id}/{name}\" doesnt match its {}",
+                                    mismatch.header
+                                );
+                                discard_upload(&id);
+                                let _ = packet.respond(
+                                    "400 Bad Request",
+                                    &[],
+                                    &format!(
+                                        "Upload doesnt match its \"{}\" header.\r\n",
+                                        mismatch.header
+                                    ),
+                                );
+                                return;
                             }
                         }
-                        let mut addr = address.to_string();
-                        if let Some(header_map) = packet.headers() {
-                            if let Some(host_addr) = header_map.get("Host") {
-                                addr = host_addr.to_owned();
-                                addr.push_str(":");
-                                addr.push_str(&address.port().to_string());
-                            }
+                        let result = result.and_then(|()| {
+                            file.finish().map_err(|err| {
+                                io::Error::other(format!("Failed to write to file: {err}"))
+                            })
+                        });
+                        if let Err(err) = result {
+                            // The client disconnected, timed out or sent a malformed body, so dont keep what arrived
+                            log!("Incomplete upload of \"{id}/{name}\" after {received} bytes: {err}");
+                            discard_upload(&id);
+                            reject_body(&mut packet, &err, received, content_length);
+                            return;
                         }
-                        let stored_path = if host == "zoe.soutter.com" {
-                            format!(
-                                "HTTP/1.1 200 Ok\r\n\r\nhttp://{}/files/{}/{}\r\n",
-                                addr, dir, name
-                            )
-                        } else {
-                            format!(
-                                "HTTP/1.1 200 Ok\r\n\r\nhttp://{}/{}/{}\r\n",
-                                addr, dir, name
-                            )
-                        };
-                        if packet.respond_string(&stored_path).is_err() {
-                            log!(
-                                "Failed to send user path to access file \"{}\"",
-                                file_location.display()
+                        metadata.size = received;
+                        metadata.sha256 = kept_sha256(&digests, secret.as_deref());
+                        if let Err(err) = storage::get().set_metadata(&id, &metadata) {
+                            log!("Failed to store metadata for \"{id}\": {err}");
+                            discard_upload(&id);
+                            let _ = packet.respond(
+                                "500 Internal Server Error",
+                                &[],
+                                "Failed to store file.\r\n",
                             );
+                            return;
+                        }
+                        let stored_path = format!(
+                            "{}\r\n",
+                            upload_url(&mut packet, address, &id, name, secret.as_deref())
+                        );
+                        let headers = upload_headers(&metadata);
+                        let headers: Vec<(&str, &str)> = headers
+                            .iter()
+                            .map(|(header, value)| (*header, value.as_str()))
+                            .collect();
+                        if packet.respond("200 Ok", &headers, &stored_path).is_err() {
+                            log!("Failed to send user path to access file \"{id}/{name}\"");
                         }
                     } else {
-                        log!("Failed to create file \"{}\"", file_location.display());
+                        log!("Failed to create file \"{id}/{name}\"");
+                        discard_upload(&id);
+                        packet.close_connection(); // The body was never read
+                        let _ = packet.respond(
+                            "500 Internal Server Error",
+                            &[],
+                            "Failed to store file.\r\n",
+                        );
                     }
-                } else {
-                    log!("Failed to create folder \"{}\"", dir_location.display());
                 }
+                Err(err) => {
+                    log!("Failed to create upload: {err}");
+                    packet.close_connection();
+                    let _ = packet.respond(
+                        "500 Internal Server Error",
+                        &[],
+                        "Failed to store file.\r\n",
+                    );
+                }
+            }
+        }
+    }
+    packet.read_all();
+    log!("{packet}\n");
+}
+/// The token in a resumable upload's path, e.g. `/resumable/<token>` (under `/files/` on the personal site).
+fn resumable_token(packet: &mut HttpRequest) -> Option<String> {
+    let path = packet.path()?;
+    let path = path.strip_prefix('/')?;
+    let path = if packet.header("Host") == Some("zoe.soutter.com") {
+        path.strip_prefix("files/")?
+    } else {
+        path
+    };
+    path.strip_prefix(RESUMABLE_PATH)?
+        .strip_prefix('/')
+        .map(str::to_owned)
+}
+/// A value from the tus `Upload-Metadata` header, e.g. `filename ZmlsZS50eHQ=,filetype dGV4dC9wbGFpbg==`.
+fn tus_metadata(packet: &mut HttpRequest, key: &str) -> Option<String> {
+    packet
+        .header("Upload-Metadata")?
+        .split(',')
+        .find_map(|pair| {
+            let (name, value) = pair.trim().split_once(' ')?;
+            (name == key).then_some(value)
+        })
+        .and_then(|value| STANDARD.decode(value.trim()).ok())
+        .and_then(|value| String::from_utf8(value).ok())
+}
+/// Starts a tus resumable upload of `Upload-Length` bytes, replying with the path its chunks are sent to. The file name is taken from the path, or the `filename` in `Upload-Metadata` if the path is `/`.
+fn create_resumable(packet: &mut HttpRequest, address: SocketAddr) {
+    let tus = ("Tus-Resumable", TUS_VERSION);
+    let length = match packet.header("Upload-Length").map(str::parse::<u64>) {
+        Some(Ok(length)) => length,
+        _ => {
+            let _ = packet.respond(
+                "400 Bad Request",
+                &[tus],
+                "Invalid \"Upload-Length\" header.\r\n",
+            );
+            return;
+        }
+    };
+    let max_size = Config::get().uploads.max_size;
+    if length > max_size {
+        log!("Request rejected: resumable upload of {length} bytes");
+        let _ = packet.respond(
+            "413 Payload Too Large",
+            &[tus],
+            &format!("Uploads cannot be larger than {max_size} bytes.\r\n"),
+        );
+        return;
+    }
+    let Some(lifetime) = upload_lifetime(packet) else {
+        return;
+    };
+    let Some(max_downloads) = upload_max_downloads(packet) else {
+        return;
+    };
+    let name = packet
+        .path()
+        .map(|path| path_decode(path.trim_start_matches('/')))
+        .filter(|name| !name.is_empty())
+        .or_else(|| tus_metadata(packet, "filename"))
+        .unwrap_or_default();
+    if !valid_file_name(&name) {
+        log!("Request rejected: resumable upload of \"{name}\"");
+        let _ = packet.respond("403 Forbidden", &[tus], "File names cannot include \"..\", \"~\", \"*\" or start with \".\", \"/\" or \"\\\"\r\n");
+        return;
+    }
+    let mut metadata = new_metadata(packet, &name, lifetime, max_downloads);
+    metadata.content_type = tus_metadata(packet, "filetype");
+    metadata.upload_length = Some(length);
+    let password = packet.header("X-Upload-Password").map(str::to_owned);
+    // The secret is made now, so the chunks are encrypted as they arrive. It is given out in the upload's location, which the client sends every chunk to
+    let created = new_password_hash(password.as_deref()).and_then(|password_hash| {
+        metadata.password_hash = password_hash;
+        let secret = new_upload_secret(&mut metadata)?;
+        let token = random_hex(16)?;
+        PartialUpload::create(&token, &metadata).map(|_| (token, secret))
+    });
+    match created {
+        Ok((token, secret)) => {
+            log!("Started resumable upload \"{token}\" of \"{name}\", {length} bytes");
+            let location = upload_url(packet, address, RESUMABLE_PATH, &token, secret.as_deref());
+            let _ = packet.respond(
+                "201 Created",
+                &[tus, ("Location", &location)],
+                &format!("{location}\r\n"),
+            );
+        }
+        Err(err) => {
+            log!("Failed to start resumable upload: {err}");
+            let _ = packet.respond(
+                "500 Internal Server Error",
+                &[tus],
+                "Failed to store file.\r\n",
+            );
+        }
+    }
+}
+/// Answers a `HEAD` request for a resumable upload with how much of it has arrived, so the client knows where to carry on from.
+fn resumable_progress(packet: &mut HttpRequest, token: &str) {
+    let tus = ("Tus-Resumable", TUS_VERSION);
+    let progress =
+        PartialUpload::open(token).and_then(|partial| Ok((partial.offset()?, partial.length())));
+    match progress {
+        Ok((offset, length)) => {
+            let (offset, length) = (offset.to_string(), length.to_string());
+            let _ = packet.respond(
+                "200 Ok",
+                &[
+                    tus,
+                    ("Upload-Offset", &offset),
+                    ("Upload-Length", &length),
+                    ("Cache-Control", "no-store"),
+                ],
+                "",
+            );
+        }
+        Err(_) => {
+            let _ = packet.respond("404 Not Found", &[tus], "No such resumable upload.\r\n");
+        }
+    }
+}
+/// Appends a chunk to a tus resumable upload at its `Upload-Offset`. Once every chunk has arrived, the upload is stored and the reply carries its download link, as with `put`.
+pub fn patch(mut packet: HttpRequest, address: SocketAddr) {
+    match resumable_token(&mut packet).map(|token| PartialUpload::open(&token)) {
+        Some(Ok(partial)) => receive_chunk(&mut packet, address, partial),
+        _ => {
+            packet.close_connection(); // Dont read the body
+            let _ = packet.respond(
+                "404 Not Found",
+                &[("Tus-Resumable", TUS_VERSION)],
+                "No such resumable upload.\r\n",
+            );
+        }
+    }
+    packet.read_all();
+    log!("{packet}\n");
+}
+fn receive_chunk(packet: &mut HttpRequest, address: SocketAddr, partial: PartialUpload) {
+    let tus = ("Tus-Resumable", TUS_VERSION);
+    if packet.header("Content-Type") != Some("application/offset+octet-stream") {
+        packet.close_connection();
+        let _ = packet.respond(
+            "415 Unsupported Media Type",
+            &[tus],
+            "Chunks must be sent as \"application/offset+octet-stream\".\r\n",
+        );
+        return;
+    }
+    let secret = match upload_secret(packet, &partial.metadata) {
+        Ok(secret) => secret,
+        Err(_) => {
+            packet.close_connection();
+            let _ = packet.respond(
+                "403 Forbidden",
+                &[tus],
+                "The upload is encrypted, send chunks to the full location it was created with, including its key.\r\n",
+            );
+            return;
+        }
+    };
+    let Some(_claim) = partial.claim() else {
+        packet.close_connection();
+        let _ = packet.respond(
+            "409 Conflict",
+            &[tus],
+            "Another chunk is still arriving.\r\n",
+        );
+        return;
+    };
+    let offset = match partial.offset() {
+        Ok(offset) => offset,
+        Err(err) => {
+            log!("Failed to read resumable upload: {err}");
+            packet.close_connection();
+            let _ = packet.respond(
+                "500 Internal Server Error",
+                &[tus],
+                "Failed to store file.\r\n",
+            );
+            return;
+        }
+    };
+    let length = partial.length();
+    let offset_header = offset.to_string();
+    if packet
+        .header("Upload-Offset")
+        .and_then(|offset| offset.parse().ok())
+        != Some(offset)
+    {
+        packet.close_connection();
+        let _ = packet.respond(
+            "409 Conflict",
+            &[tus, ("Upload-Offset", &offset_header)],
+            &format!("The upload carries on from offset {offset}.\r\n"),
+        );
+        return;
+    }
+    let content_length = packet.content_length().and_then(Result::ok);
+    if content_length.is_some_and(|content_length| content_length > length - offset) {
+        packet.close_connection();
+        let _ = packet.respond(
+            "400 Bad Request",
+            &[tus],
+            &format!("Only {} bytes of the upload are left.\r\n", length - offset),
+        );
+        return;
+    }
+    send_continue(packet);
+    let mut received: u64 = 0;
+    // Whatever arrives is kept even if the chunk is cut short, so the client can carry on from there
+    let result = partial.append().and_then(|file| {
+        let mut file: Box<dyn Write> = match &secret {
+            Some(secret) => Box::new(PartialEncryption::new(
+                file,
+                &UploadKey::from_secret(secret),
+                offset,
+            )),
+            None => Box::new(file),
+        };
+        let mut body = packet.body()?;
+        receive_body(
+            &mut (&mut body).take(length - offset),
+            &mut file,
+            &mut received,
+        )?;
+        if body.read(&mut [0u8])? > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "chunk goes past the end of the upload",
+            ));
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        log!("Chunk of resumable upload cut short after {received} bytes: {err}");
+        reject_body(packet, &err, received, content_length);
+        return;
+    }
+    let offset_header = (offset + received).to_string();
+    if offset + received < length {
+        let _ = packet.respond(
+            "204 No Content",
+            &[tus, ("Upload-Offset", &offset_header)],
+            "",
+        );
+        return;
+    }
+    let mut metadata = partial.metadata.clone();
+    // The upload's lifetime starts once it is complete, rather than when the first chunk arrived
+    let lifetime = Duration::from_secs(metadata.expires.saturating_sub(metadata.created));
+    let completed = Metadata::new(&metadata.file_name, lifetime);
+    metadata.created = completed.created;
+    metadata.expires = completed.expires;
+    metadata.upload_length = None;
+    metadata.delete_token = new_delete_token();
+    match store_resumable(&partial, &mut metadata, secret.as_deref()) {
+        Ok(id) => {
+            if let Err(err) = partial.remove() {
+                log!("Failed to delete finished resumable upload: {err}");
+            }
+            let stored_path = format!(
+                "{}\r\n",
+                upload_url(packet, address, &id, &metadata.file_name, secret.as_deref())
+            );
+            let mut headers = upload_headers(&metadata);
+            headers.push(("Upload-Offset", offset_header));
+            headers.push(("Tus-Resumable", TUS_VERSION.to_owned()));
+            let headers: Vec<(&str, &str)> = headers
+                .iter()
+                .map(|(header, value)| (*header, value.as_str()))
+                .collect();
+            let _ = packet.respond("200 Ok", &headers, &stored_path);
+        }
+        Err(err) => {
+            // The chunks are kept, so an empty chunk at the end can try storing it again
+            log!("Failed to store resumable upload: {err}");
+            let _ = packet.respond(
+                "500 Internal Server Error",
+                &[tus],
+                "Failed to store file.\r\n",
+            );
+        }
+    }
+}
+/// Copies a resumable upload whose chunks have all arrived into storage, returning its id. An encrypted upload needs the `secret` its chunks were encrypted with.
+fn store_resumable(
+    partial: &PartialUpload,
+    metadata: &mut Metadata,
+    secret: Option<&str>,
+) -> io::Result<String> {
+    let id = create_upload(metadata)?;
+    let stored = put_upload(&id, secret).and_then(|file| {
+        let mut file = HashingWriter::new(file, &[]);
+        let mut data = partial.data()?;
+        metadata.size = match secret {
+            Some(secret) => io::copy(
+                &mut PartialEncryption::new(&mut data, &UploadKey::from_secret(secret), 0),
+                &mut file,
+            )?,
+            None => io::copy(&mut data, &mut file)?,
+        };
+        let (file, digests) = file.finish();
+        file.finish()?;
+        metadata.sha256 = kept_sha256(&digests, secret);
+        storage::get().set_metadata(&id, metadata)
+    });
+    if let Err(err) = stored {
+        discard_upload(&id);
+        return Err(err);
+    }
+    Ok(id)
+}
+/// Stores each file in a `multipart/form-data` body (as sent by a browser's upload form) in its own folder, streaming them to disk, and replies with their download links.
+pub fn post(mut packet: HttpRequest, address: SocketAddr) {
+    if packet.header("Upload-Length").is_some() {
+        create_resumable(&mut packet, address);
+        packet.read_all();
+        log!("{packet}\n");
+        return;
+    }
+    let Some(boundary) = packet
+        .header("Content-Type")
+        .and_then(Multipart::<io::Empty>::boundary)
+    else {
+        log!("Request rejected: POST without a multipart/form-data body");
+        packet.close_connection(); // Dont read the body
+        let _ = packet.respond(
+            "415 Unsupported Media Type",
+            &[],
+            "Uploads must be \"multipart/form-data\" with a boundary, or use PUT.\r\n",
+        );
+        packet.read_all();
+        log!("{packet}\n");
+        return;
+    };
+    let Some(content_length) = upload_length(&mut packet) else {
+        packet.read_all();
+        log!("{packet}\n");
+        return;
+    };
+    let Some(lifetime) = upload_lifetime(&mut packet) else {
+        packet.read_all();
+        log!("{packet}\n");
+        return;
+    };
+    let Some(max_downloads) = upload_max_downloads(&mut packet) else {
+        packet.read_all();
+        log!("{packet}\n");
+        return;
+    };
+    send_continue(&mut packet);
+    let mut template = new_metadata(&mut packet, "", lifetime, max_downloads);
+    let mut stored: Vec<(String, Metadata, Option<String>)> = Vec::new(); // Id, metadata and secret of each file
+    let mut received: u64 = 0;
+    let result = packet.body().and_then(|body| {
+        let mut form = Multipart::new(body, &boundary);
+        while let Some(part) = form.next_part()? {
+            if part.name.as_deref() == Some("password") && part.filename.is_none() {
+                // Files are stored as they arrive, so they cant be protected after the fact
+                if !stored.is_empty() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "the password field has to come before the files",
+                    ));
+                }
+                let mut password = String::new();
+                (&mut form)
+                    .take(MAX_PASSWORD_LENGTH + 1)
+                    .read_to_string(&mut password)?;
+                if password.len() as u64 > MAX_PASSWORD_LENGTH {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("the password is longer than {MAX_PASSWORD_LENGTH} bytes"),
+                    ));
+                }
+                template.password_hash =
+                    new_password_hash(Some(&password)).map_err(io::Error::other)?;
+                continue;
+            }
+            let Some(filename) = part.filename.filter(|filename| !filename.is_empty()) else {
+                continue; // Not a file, or an empty file input
+            };
+            // Some browsers send the full path the file was picked from
+            let name = filename
+                .rsplit(['/', '\\'])
+                .next()
+                .unwrap_or_default()
+                .to_owned();
+            if !valid_file_name(&name) {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    format!("Invalid file name \"{name}\""),
+                ));
+            }
+            let mut metadata = template.clone();
+            metadata.file_name = name;
+            metadata.content_type = part.content_type;
+            metadata.delete_token = new_delete_token();
+            let secret = new_upload_secret(&mut metadata).map_err(io::Error::other)?;
+            let id = create_upload(&metadata).map_err(io::Error::other)?;
+            stored.push((id.clone(), metadata.clone(), secret.clone()));
+            let file = put_upload(&id, secret.as_deref()).map_err(io::Error::other)?;
+            let mut file = HashingWriter::new(file, &[]);
+            let start = received;
+            receive_body(&mut form, &mut file, &mut received)?;
+            let (file, digests) = file.finish();
+            file.finish()
+                .map_err(|err| io::Error::other(format!("Failed to write to file: {err}")))?;
+            metadata.size = received - start;
+            metadata.sha256 = kept_sha256(&digests, secret.as_deref());
+            storage::get()
+                .set_metadata(&id, &metadata)
+                .map_err(io::Error::other)?;
+            if let Some((_, stored_metadata, _)) = stored.last_mut() {
+                *stored_metadata = metadata;
             }
+        }
+        Ok(())
+    });
+    if let Err(err) = result {
+        log!("Incomplete multipart upload after {received} bytes: {err}");
+        for (id, _, _) in &stored {
+            discard_upload(id);
+        }
+        if err.kind() == io::ErrorKind::PermissionDenied {
+            packet.close_connection();
+            let _ = packet.respond("403 Forbidden", &[], "File names cannot include \"..\", \"~\", \"*\" or start with \".\", \"/\" or \"\\\"\r\n");
+        } else {
+            reject_body(&mut packet, &err, received, content_length);
+        }
+    } else if stored.is_empty() {
+        log!("Request rejected: POST without any files");
+        let _ = packet.respond("400 Bad Request", &[], "No files were uploaded.\r\n");
+    } else {
+        let urls: Vec<String> = stored
+            .iter()
+            .map(|(id, metadata, secret)| {
+                upload_url(
+                    &mut packet,
+                    address,
+                    id,
+                    &metadata.file_name,
+                    secret.as_deref(),
+                )
+            })
+            .collect();
+        let mut headers = upload_headers(&template);
+        // Tokens line up with the links, an empty entry means that file cant be deleted early
+        let delete_tokens = stored
+            .iter()
+            .map(|(_, metadata, _)| metadata.delete_token.as_deref().unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join(", ");
+        headers.push(("X-Delete-Token", delete_tokens));
+        let digests = stored
+            .iter()
+            .map(|(_, metadata, _)| metadata.sha256.as_deref().unwrap_or_default())
+            .collect::<Vec<_>>()
+            .join(", ");
+        headers.push(("X-Sha256", digests));
+        let wants_html = packet
+            .header("Accept")
+            .is_some_and(|accept| accept.contains("text/html"));
+        let body = if wants_html {
+            let links: String = urls
+                .iter()
+                .map(|url| {
+                    let url = html_escape(url);
+                    format!("<li><a href=\"{url}\">{url}</a></li>")
+                })
+                .collect();
+            headers.push(("Content-Type", "text/html; charset=utf-8".to_owned()));
+            format!("<!DOCTYPE html><html><body><p>Uploaded:</p><ul>{links}</ul></body></html>\r\n")
         } else {
-            log!("Failed to get system time");
+            format!("{}\r\n", urls.join("\r\n"))
+        };
+        let headers: Vec<(&str, &str)> = headers
+            .iter()
+            .map(|(header, value)| (*header, value.as_str()))
+            .collect();
+        if packet.respond("200 Ok", &headers, &body).is_err() {
+            log!("Failed to send user paths to access files");
         }
     }
     packet.read_all();
@@ -138,19 +1224,15 @@ pub fn ip_page(packet: &mut HttpRequest, address: SocketAddr) {
         // Make a no_html check
         let peer_ip = packet
             .body_stream()
-            .peer_addr()
+            .and_then(|tcp| tcp.peer_addr().ok())
             .map(|addr| addr.ip().to_string())
             .unwrap_or("UNKNOWN".to_string());
-        let _ = packet.respond_string(&format!("HTTP/1.1 200 Ok\r\n\r\n{}", peer_ip));
+        let _ = packet.respond("200 Ok", &[], &peer_ip);
     } else {
         //let _ = packet.respond_string("HTTP/1.1 200 OK\r\n\r\n");
         //let _ =
         //    packet.respond_data(&std::fs::read("site/files.html").expect("Missing files page."));
     }
-    packet
-        .body_stream()
-        .shutdown(std::net::Shutdown::Both)
-        .unwrap();
 }
 pub fn files_page(packet: &mut HttpRequest, address: SocketAddr) {
     let no_html;
@@ -168,31 +1250,405 @@ pub fn files_page(packet: &mut HttpRequest, address: SocketAddr) {
         if let Some(header_map) = packet.headers() {
             if let Some(host_addr) = header_map.get("Host") {
                 addr = host_addr.to_owned();
-                addr.push_str(":");
+                addr.push(':');
                 addr.push_str(&address.port().to_string());
             }
         }
-        let _ = packet.respond_string( &format!("HTTP/1.1 200 Ok\r\n\r\nTo upload, type:\r\n$ curl --upload-file <filename> http://{addr}\r\n\r\nThen to download, type:\r\n$ curl http://{addr}/files/<file_id>/<file_name> --output filename.txt\r\n\r\nIf you would like this output to be in HTML, please add \"text/html\" as an accepted format in your \"Accept\" header."));
+        let (lifetime, max_lifetime) = (
+            Config::get().gc.lifetime_secs,
+            Config::get().gc.max_lifetime_secs,
+        );
+        let _ = packet.respond("200 Ok", &[], &format!("To upload, type:\r\n$ curl --upload-file <filename> http://{addr}\r\n\r\nThen to download, type:\r\n$ curl http://{addr}/files/<file_id>/<file_name> --output filename.txt\r\n\r\nUploads are kept for {lifetime} seconds, to change that add the header \"X-Expires-In: <seconds>\" when uploading (at most {max_lifetime} seconds).\r\n\r\nTo delete it after it has been downloaded a number of times, add the header \"X-Max-Downloads: <count>\" when uploading.\r\n\r\nTo require a password to download it, add the header \"X-Upload-Password: <password>\" when uploading, then download with:\r\n$ curl -u :<password> http://{addr}/files/<file_id>/<file_name> --output filename.txt\r\n\r\nLarge uploads can be resumed if they are cut off by using a tus client, with http://{addr}/ as the endpoint.\r\n\r\nTo delete it early, use the \"X-Delete-Token\" header from the upload response (shown by curl -i):\r\n$ curl -X DELETE -H \"X-Delete-Token: <token>\" http://{addr}/files/<file_id>/<file_name>\r\n\r\nIf you would like this output to be in HTML, please add \"text/html\" as an accepted format in your \"Accept\" header."));
     } else {
-        let _ = packet.respond_string("HTTP/1.1 200 OK\r\n\r\n");
-        let _ =
-            packet.respond_data(&std::fs::read("site/files.html").expect("Missing files page."));
+        let page = std::fs::read(SITE_PATH.join("files.html")).expect("Missing files page.");
+        let _ = packet.respond_head(
+            "200 OK",
+            &[("Content-Type", "text/html; charset=utf-8")],
+            Some(page.len() as u64),
+        );
+        let _ = packet.respond_data(&page);
+    }
+}
+/// Sends a file as the body of a `200 Ok` response, or the parts a `Range` header asks for as `206 Partial Content`, or just the headers if this is a `HEAD` request. Conditional requests may get `304 Not Modified` or `412 Precondition Failed` instead.
+/// # Errors
+/// Fails without sending anything if the file is an upload whose last download was taken by another request.
+fn send_file(packet: &mut HttpRequest, download: Download) -> io::Result<()> {
+    let Download {
+        mut file,
+        metadata,
+        id,
+        modified,
+        content_type,
+    } = download;
+    let len = file.len();
+    let content_type = content_type.unwrap_or_else(|| sniff_type(file.as_mut()));
+    // Only uploads, as the site's own pages are meant to be shown
+    let disposition = metadata.as_ref().map(|metadata| {
+        let disposition = upload_disposition(packet, &content_type);
+        let header = content_disposition(&disposition, &metadata.file_name);
+        (disposition, header)
+    });
+    let content_type = match &disposition {
+        Some((Disposition::InlineAsText, _)) => "text/plain; charset=utf-8".to_owned(),
+        _ => content_type,
+    };
+    // Every request for an upload with a download limit gets the whole file, and is counted, so ranges cant be used to fetch it piece by piece without using up downloads
+    let limited = metadata
+        .as_ref()
+        .is_some_and(|metadata| metadata.max_downloads.is_some());
+    let protected = metadata.as_ref().map(|metadata| {
+        limited || metadata.password_hash.is_some() || metadata.key_check.is_some()
+    });
+    // An encrypted upload's digest, if storage kept one, is of what is on disk rather than what is sent
+    let sha256 = metadata
+        .filter(|metadata| metadata.key_check.is_none())
+        .and_then(|metadata| metadata.sha256);
+    // Files without a digest, like the site's, change whenever their size or modification time does
+    let etag = match &sha256 {
+        Some(sha256) => format!("\"{sha256}\""),
+        None => {
+            let secs = modified
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_secs())
+                .unwrap_or(0);
+            format!("\"{secs:x}-{len:x}\"")
+        }
+    };
+    let mut headers = vec![
+        // The type is worked out carefully, so browsers shouldnt second guess it
+        ("X-Content-Type-Options", "nosniff".to_owned()),
+        (
+            "Accept-Ranges",
+            if limited { "none" } else { "bytes" }.to_owned(),
+        ),
+        ("ETag", etag.clone()),
+        ("Last-Modified", http_date(modified)),
+    ];
+    if let Some((_, header)) = disposition {
+        headers.push(("Content-Disposition", header));
+    }
+    // Lets clients check the file arrived intact
+    if let Some(digest) = sha256.as_deref().and_then(checksum::digest_header) {
+        headers.push(("Digest", digest));
+    }
+    let path = packet.path().unwrap_or_default();
+    let cache_control = Config::get().cache_control(&path);
+    let cache_control = match protected {
+        Some(protected) => Some(upload_cache_control(cache_control, protected)),
+        None => cache_control.map(str::to_owned),
+    };
+    if let Some(cache_control) = cache_control {
+        headers.push(("Cache-Control", cache_control));
+    }
+    match conditional::evaluate(packet, &etag, modified) {
+        Precondition::Send => {}
+        Precondition::NotModified => {
+            headers.push(("Content-Type", content_type));
+            let _ = packet.respond_head("304 Not Modified", &header_refs(&headers), Some(len));
+            return Ok(());
+        }
+        Precondition::Failed => {
+            let _ = packet.respond(
+                "412 Precondition Failed",
+                &header_refs(&headers),
+                "The file has changed.\r\n",
+            );
+            return Ok(());
+        }
+    }
+    // A range of a file which has changed since the client's copy would be garbage, so it gets the whole file
+    let ranges = match packet.header("Range").map(str::to_owned) {
+        Some(range) if !limited && conditional::if_range_matches(packet, &etag, modified) => {
+            range::parse(&range, len)
+        }
+        _ => Ranges::Full,
+    };
+    // Resuming a download or seeking in a video carries on a download which was already counted, which can only happen without a limit
+    let starts_download = match &ranges {
+        Ranges::Full => true,
+        Ranges::Partial(ranges) => ranges.iter().any(|(start, _)| *start == 0),
+        Ranges::Unsatisfiable => false,
+    };
+    // Held until the upload is deleted below, if this was its last download
+    let _sending = id.as_deref().map(storage::Sending::new);
+    let last_download = match id.as_deref() {
+        Some(id) if starts_download && !packet.is_head() => {
+            storage::count_download(storage::get(), id)?
+        }
+        _ => false,
+    };
+    send_ranges(packet, file.as_mut(), headers, content_type, ranges, len);
+    if let Some(id) = id.filter(|_| last_download) {
+        log!("Sent last download of \"{id}\", deleting it");
+        if let Err(err) = storage::get().delete(&id) {
+            log!("Failed to delete \"{id}\": {err}");
+        }
+    }
+    Ok(())
+}
+/// Sends the response body for [`send_file`], the whole file or the parts of it in `ranges`.
+fn send_ranges(
+    packet: &mut HttpRequest,
+    file: &mut dyn StoredFile,
+    mut headers: Vec<(&str, String)>,
+    content_type: String,
+    ranges: Ranges,
+    len: u64,
+) {
+    match ranges {
+        Ranges::Full => {
+            headers.push(("Content-Type", content_type));
+            let headers = header_refs(&headers);
+            let _ = packet.respond_head("200 Ok", &headers, Some(len)); // Send header so client is ready to receive file
+            if !packet.is_head() {
+                send_bytes(packet, file, 0, len);
+            }
+        }
+        Ranges::Unsatisfiable => {
+            headers.push(("Content-Range", format!("bytes */{len}")));
+            let _ = packet.respond(
+                "416 Range Not Satisfiable",
+                &header_refs(&headers),
+                &format!("The file is only {len} bytes long.\r\n"),
+            );
+        }
+        Ranges::Partial(ranges) if ranges.len() == 1 => {
+            let (start, end) = ranges[0];
+            headers.push(("Content-Type", content_type));
+            headers.push(("Content-Range", format!("bytes {start}-{end}/{len}")));
+            let _ = packet.respond_head(
+                "206 Partial Content",
+                &header_refs(&headers),
+                Some(end - start + 1),
+            );
+            if !packet.is_head() {
+                send_bytes(packet, file, start, end - start + 1);
+            }
+        }
+        Ranges::Partial(ranges) => {
+            let boundary = match random_hex(16) {
+                Ok(boundary) => boundary,
+                Err(err) => {
+                    log!("Failed to make multipart boundary: {err}");
+                    packet.close_connection();
+                    let _ = packet.respond(
+                        "500 Internal Server Error",
+                        &[],
+                        "Failed to read file.\r\n",
+                    );
+                    return;
+                }
+            };
+            // Each range gets its own headers, which have to be counted up front for the Content-Length
+            let body = Byteranges::new(&boundary, &content_type, &ranges, len);
+            headers.push((
+                "Content-Type",
+                format!("multipart/byteranges; boundary={boundary}"),
+            ));
+            let _ = packet.respond_head(
+                "206 Partial Content",
+                &header_refs(&headers),
+                Some(body.len()),
+            );
+            if packet.is_head() {
+                return;
+            }
+            for (part_head, start, count) in &body.parts {
+                if packet.respond_data(part_head.as_bytes()).is_err()
+                    || !send_bytes(packet, file, *start, *count)
+                    || packet.respond_data(b"\r\n").is_err()
+                {
+                    return;
+                }
+            }
+            let _ = packet.respond_data(body.closing.as_bytes());
+        }
+    }
+}
+/// The `Cache-Control` for an upload, from the rule for its path. Uploads are always `private`, so shared caches never keep them, and ones with a password, key or download limit arent kept anywhere, as the cache would outlive what protects them.
+fn upload_cache_control(rule: Option<&str>, protected: bool) -> String {
+    if protected {
+        return "private, no-store".to_owned();
     }
+    let mut directives: Vec<&str> = rule
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|directive| {
+            let name = directive.split('=').next().unwrap_or_default().trim();
+            !name.is_empty()
+                && !name.eq_ignore_ascii_case("public")
+                && !name.eq_ignore_ascii_case("s-maxage")
+        })
+        .collect();
+    if !directives
+        .iter()
+        .any(|directive| directive.eq_ignore_ascii_case("private"))
+    {
+        directives.insert(0, "private");
+    }
+    directives.join(", ")
+}
+/// Guesses the type of a file from its start, see [`mime::sniff`].
+fn sniff_type(file: &mut dyn StoredFile) -> String {
+    let mut start = Vec::with_capacity(mime::SNIFF_LEN);
+    match (&mut *file)
+        .take(mime::SNIFF_LEN as u64)
+        .read_to_end(&mut start)
+    {
+        Ok(_) => mime::sniff(&start).to_owned(),
+        Err(err) => {
+            log!("Failed to read file to guess its type: {err}");
+            "application/octet-stream".to_owned()
+        }
+    }
+}
+/// How an upload is handed to the browser, see [`upload_disposition`].
+#[derive(Debug, PartialEq, Eq)]
+enum Disposition {
+    Inline,
+    /// Shown as `text/plain`, so markup and scripts are displayed rather than run.
+    InlineAsText,
+    Attachment,
 }
-// Reads the requested path, and if it matches a file on the server, returns the file in the body
+/// Uploads are downloaded unless their type is one of `uploads.inline_types`. `?download` always downloads, and `?inline` shows text of any other type as plain text; anything else cant be shown safely, so it is still downloaded.
+fn upload_disposition(packet: &mut HttpRequest, content_type: &str) -> Disposition {
+    if packet.query("download").is_some() {
+        Disposition::Attachment
+    } else if Config::get().uploads.is_inline(content_type) {
+        Disposition::Inline
+    } else if packet.query("inline").is_some() && mime::is_textual(content_type) {
+        Disposition::InlineAsText
+    } else {
+        Disposition::Attachment
+    }
+}
+/// A `Content-Disposition` header naming the file, with `filename*` (RFC 8187) for the real name and `filename` as an ASCII fallback for old clients.
+fn content_disposition(disposition: &Disposition, name: &str) -> String {
+    let kind = match disposition {
+        Disposition::Inline | Disposition::InlineAsText => "inline",
+        Disposition::Attachment => "attachment",
+    };
+    let fallback: String = name
+        .chars()
+        .map(|char| match char {
+            ' ' => ' ',
+            '"' | '\\' | '%' => '_',
+            char if char.is_ascii_graphic() => char,
+            _ => '_',
+        })
+        .collect();
+    let encoded: String =
+        name.bytes()
+            .map(|byte| match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => (byte as char).to_string(),
+                b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|'
+                | b'~' => (byte as char).to_string(),
+                _ => format!("%{byte:02X}"),
+            })
+            .collect();
+    format!("{kind}; filename=\"{fallback}\"; filename*=UTF-8''{encoded}")
+}
+/// Borrows owned header values in the form `respond` takes.
+fn header_refs<'a>(headers: &'a [(&'a str, String)]) -> Vec<(&'a str, &'a str)> {
+    headers
+        .iter()
+        .map(|(header, value)| (*header, value.as_str()))
+        .collect()
+}
+/// Sends `count` bytes of the file from `start`, returning false if the response was cut short.
+fn send_bytes(packet: &mut HttpRequest, file: &mut dyn StoredFile, start: u64, count: u64) -> bool {
+    if let Err(err) = file.seek(SeekFrom::Start(start)) {
+        log!("Failed to seek in file: \"{err}\"");
+        packet.close_connection(); // The client cant tell the file was cut short otherwise
+        return false;
+    }
+    let mut remaining = count;
+    let mut buf = [0u8; 1024];
+    while remaining > 0 {
+        let wanted = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        match file.read(&mut buf[..wanted]) {
+            Ok(0) => {
+                log!("File ended {remaining} bytes early");
+                packet.close_connection();
+                return false;
+            }
+            Ok(num) => {
+                if packet.respond_data(&buf[0..num]).is_err() {
+                    return false;
+                }
+                remaining -= num as u64;
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => {
+                log!("Stopped writing to file: \"{err}\"");
+                packet.close_connection();
+                return false;
+            }
+        }
+    }
+    true
+}
+/// Sends a file opened for `get`, or explains why it couldnt be opened.
+fn send_download(packet: &mut HttpRequest, name: &str, file: io::Result<Download>) {
+    match file.and_then(|file| send_file(packet, file)) {
+        Ok(()) => {}
+        Err(err)
+            if err
+                .get_ref()
+                .is_some_and(|err| err.is::<PasswordRequired>()) =>
+        {
+            let _ = packet.respond(
+                "401 Unauthorized",
+                &[("WWW-Authenticate", "Basic realm=\"upload\", charset=\"UTF-8\"")],
+                &format!("\"{name}\" needs a password, send it with HTTP Basic auth or as \"?password=\".\r\n"),
+            );
+            log!("Client requested \"{name}\" without its password");
+        }
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            let _ = packet.respond(
+                "403 Forbidden",
+                &[],
+                &format!("\"{name}\" is encrypted, use the full link it was uploaded with, including its key.\r\n"),
+            );
+            log!("Client requested \"{name}\" without its key");
+        }
+        Err(_) => {
+            let _ = packet.respond(
+                "410 Gone",
+                &[],
+                &format!(
+                    "Failed to fetch \"{name}\", this is likely because it doesn't exist.\r\n"
+                ),
+            );
+            log!("Client requested non-existent file \"{name}\"");
+        }
+    }
+}
+// Reads the requested path, and if it matches a file on the server, returns the file in the body. Also answers `HEAD` requests, which get the same headers without the body
 pub fn get(mut packet: HttpRequest, address: SocketAddr) {
-    let host = packet.headers().unwrap().get("Host").unwrap();
+    if packet.is_head() {
+        if let Some(token) = resumable_token(&mut packet) {
+            resumable_progress(&mut packet, &token);
+            packet.read_all();
+            log!("{packet}\n");
+            return;
+        }
+    }
+    let host = packet.header("Host").unwrap_or_default().to_owned(); // HTTP/1.0 clients may not send one
     log!("Requesting from {host}");
     if host == "zoe.soutter.com" {
         log!("Requesting from Personal site");
         if let Some(mut name) = packet.path() {
             println!("NAME: {}", name);
-            if name == "" || name == "/" {
+            if name.is_empty() || name == "/" {
                 name = "/index.html".to_owned();
             } else if name == "/files" {
                 files_page(&mut packet, address);
                 return;
-            } else if name == "/ip".to_owned() {
+            } else if name == "/ip" {
                 ip_page(&mut packet, address);
                 return;
             } else if name.starts_with("/email") {
@@ -202,115 +1658,739 @@ pub fn get(mut packet: HttpRequest, address: SocketAddr) {
             }
             let name = &name[1..];
 
-            let file_location = if name.starts_with("files/") {
-                PathBuf::from(ROOT_PATH.as_path())
-                    .join(name)
-                    .canonicalize()
-                    .expect(&format!(
-                        "Client requested non-existent file {}",
-                        PathBuf::from(ROOT_PATH.as_path()).join(name).display()
-                    ))
+            let file = if let Some(path) = name.strip_prefix("files/") {
+                open_files_path(&mut packet, path)
             } else {
-                PathBuf::from(SITE_PATH.as_path())
-                    .join(name)
-                    .canonicalize()
-                    .expect(&format!(
-                        "Client requested non-existent file {}",
-                        PathBuf::from(SITE_PATH.as_path()).join(name).display()
-                    ))
+                open_static(&SITE_PATH, name)
             };
-            if !file_location.starts_with(ROOT_PATH.clone())
-                && !file_location.starts_with(SITE_PATH.clone())
-            {
-                panic!("User attempted path traversal");
-            }
 
             log!("Attempting to open {}", &name);
-            if let Ok(mut file) = std::fs::OpenOptions::new().read(true).open(file_location) {
-                let _ = packet.respond_string("HTTP/1.1 200 Ok\r\n"); // Send header so client is ready to receive file
-                let _ = packet.respond_string(&format!(
-                    "Content-length: {}\r\n",
-                    file.metadata().unwrap().len()
-                ));
-                let _ = packet.respond_string("\r\n");
-                loop {
-                    let mut buf = [0u8; 1024];
-                    match file.read(&mut buf) {
-                        Ok(num) => {
-                            if num == 0 {
-                                break;
-                            }
-                            packet.respond_data(&buf[0..num]).unwrap();
-                        }
-                        Err(err) => match err.kind() {
-                            io::ErrorKind::WouldBlock => break,
-                            err => {
-                                log!("Stopped writing to file: \"{err}\"");
-                                break;
-                            }
-                        },
-                    }
-                }
-            } else {
-                packet.respond_string( &format!("HTTP/1.1 410 Gone\r\n\r\nFailed to fetch \"{name}\", this is likely because it doesn't exist.\r\n")).unwrap();
-                log!("Client requested non-existent file \"{name}\"");
-            }
+            send_download(&mut packet, name, file);
         }
         packet.read_all();
         log!("{packet}\n");
     } else {
         if let Some(name) = packet.path() {
-            let file_location = if name == "" || name == "/" {
-                PathBuf::from(SITE_PATH.as_path()).join("files.txt")
+            let file = if name.is_empty() || name == "/" {
+                open_static(&SITE_PATH, "files.txt")
             } else {
-                let name = &name[1..];
-
-                let file_location = PathBuf::from(ROOT_PATH.as_path())
-                    .join("files")
-                    .join(name)
-                    .canonicalize()
-                    .expect(&format!(
-                        "Client requested non-existent file {}",
-                        PathBuf::from(ROOT_PATH.as_path()).join(name).display()
-                    ));
-                if !file_location.starts_with(ROOT_PATH.join("files/")) {
-                    panic!("User attempted path traversal");
-                }
-                file_location
+                open_files_path(&mut packet, &name[1..])
             };
 
             log!("Attempting to open {}", &name);
-            if let Ok(mut file) = std::fs::OpenOptions::new().read(true).open(file_location) {
-                let _ = packet.respond_string("HTTP/1.1 200 Ok\r\n"); // Send header so client is ready to receive file
-                let _ = packet.respond_string(&format!(
-                    "Content-length: {}\r\n",
-                    file.metadata().unwrap().len()
-                ));
-                let _ = packet.respond_string("\r\n");
-                loop {
-                    let mut buf = [0u8; 1024];
-                    match file.read(&mut buf) {
-                        Ok(num) => {
-                            if num == 0 {
-                                break;
+            send_download(&mut packet, &name, file);
+        }
+        packet.read_all();
+        log!("{packet}\n");
+    }
+}
+/// Deletes an upload early, if the request carries the `X-Delete-Token` returned when it was uploaded.
+pub fn delete(mut packet: HttpRequest, _address: SocketAddr) {
+    let personal_site = packet.header("Host") == Some("zoe.soutter.com");
+    if let Some(path) = packet.path() {
+        let name = path.strip_prefix('/').unwrap_or_default(); // Anything else cant name an upload, so gets a 404
+        let name = if personal_site {
+            name.strip_prefix("files/").unwrap_or(name)
+        } else {
+            name
+        };
+        let storage = storage::get();
+        let metadata = upload_target(name).and_then(|(id, file_name)| {
+            storage
+                .stat(id)
+                .ok()
+                .filter(|metadata| metadata.file_name == path_decode(file_name))
+                .map(|metadata| (id, metadata))
+        });
+        let token = packet.header("X-Delete-Token").map(str::to_owned);
+        if let Some((id, metadata)) = metadata {
+            if let Some(token) = token {
+                match metadata.delete_token {
+                    Some(expected) if constant_time_eq(expected.as_bytes(), token.as_bytes()) => {
+                        match storage.delete(id) {
+                            Ok(()) => {
+                                log!("Deleted \"{id}\" on request");
+                                let _ = packet.respond("200 Ok", &[], "Deleted.\r\n");
                             }
-                            packet.respond_data(&buf[0..num]).unwrap();
-                        }
-                        Err(err) => match err.kind() {
-                            io::ErrorKind::WouldBlock => break,
-                            err => {
-                                log!("Stopped writing to file: \"{err}\"");
-                                break;
+                            Err(err) => {
+                                log!("Failed to delete \"{id}\": {err}");
+                                let _ = packet.respond(
+                                    "500 Internal Server Error",
+                                    &[],
+                                    "Failed to delete upload.\r\n",
+                                );
                             }
-                        },
+                        }
+                    }
+                    _ => {
+                        log!("Client gave wrong delete token for \"{name}\"");
+                        let _ = packet.respond("403 Forbidden", &[], "Incorrect delete token.\r\n");
                     }
                 }
             } else {
-                packet.respond_string( &format!("HTTP/1.1 410 Gone\r\n\r\nFailed to fetch \"{name}\", this is likely because it doesn't exist.\r\n")).unwrap();
-                log!("Client requested non-existent file \"{name}\"");
+                let _ = packet.respond(
+                    "403 Forbidden",
+                    &[],
+                    "Deleting requires the \"X-Delete-Token\" header returned when uploading.\r\n",
+                );
             }
+        } else {
+            let _ = packet.respond("404 Not Found", &[], "No such upload.\r\n");
+            log!("Client tried to delete non-existent file \"{name}\"");
         }
-        packet.read_all();
-        log!("{packet}\n");
+    }
+    packet.read_all();
+    log!("{packet}\n");
+}
+/// Compares two byte strings in time independent of where they differ, so tokens cant be guessed byte by byte.
+fn constant_time_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0u8, |diff, (left, right)| diff | (left ^ right))
+            == 0
+}
+/// Lists the methods the server supports.
+pub fn options(mut packet: HttpRequest, _address: SocketAddr) {
+    let max_size = Config::get().uploads.max_size.to_string();
+    let _ = packet.respond(
+        "204 No Content",
+        &[
+            ("Allow", ALLOWED_METHODS),
+            ("Tus-Resumable", TUS_VERSION),
+            ("Tus-Version", TUS_VERSION),
+            ("Tus-Extension", "creation"),
+            ("Tus-Max-Size", &max_size),
+        ],
+        "",
+    );
+    packet.read_all();
+    log!("{packet}\n");
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{setup, Response};
+
+    const ADDRESS: &str = "127.0.0.1:8080";
+
+    /// Runs `handler` on a request with `headers` and `body`, returning what it sent back.
+    fn send(
+        handler: fn(HttpRequest, SocketAddr),
+        line: &str,
+        headers: &[&str],
+        body: &[u8],
+    ) -> Response {
+        setup();
+        let mut raw = format!("{line} HTTP/1.1\r\nHost: localhost\r\n");
+        for header in headers {
+            raw.push_str(&format!("{header}\r\n"));
+        }
+        if !body.is_empty() {
+            raw.push_str(&format!("Content-Length: {}\r\n", body.len()));
+        }
+        raw.push_str("\r\n");
+        let mut raw = raw.into_bytes();
+        raw.extend_from_slice(body);
+        let (packet, mut client) = HttpRequest::from_bytes(&raw);
+        handler(packet, ADDRESS.parse().unwrap());
+        Response::read(&mut client).expect("Handler should respond")
+    }
+    /// The path of the link in a response's body, as given to uploaders.
+    fn link(response: &Response) -> String {
+        let link = String::from_utf8_lossy(&response.body);
+        let link = link.trim();
+        link.strip_prefix("http://localhost:8080")
+            .unwrap_or(link)
+            .to_owned()
+    }
+    /// Uploads `body` as `name` with `PUT`.
+    fn upload(name: &str, body: &[u8], headers: &[&str]) -> Response {
+        let response = send(put, &format!("PUT /{name}"), headers, body);
+        assert_eq!(
+            response.status,
+            200,
+            "{}",
+            String::from_utf8_lossy(&response.body)
+        );
+        response
+    }
+    fn download(path: &str, headers: &[&str]) -> Response {
+        send(get, &format!("GET {path}"), headers, b"")
+    }
+    /// The id of the upload a link is to.
+    fn link_id(link: &str) -> &str {
+        link[1..].split('/').next().unwrap()
+    }
+
+    #[test]
+    fn delete_needs_the_token() {
+        let uploaded = upload("delete.txt", b"bye", &[]);
+        let link = link(&uploaded);
+        let token = uploaded.header("X-Delete-Token").unwrap();
+        let line = format!("DELETE {link}");
+        assert_eq!(send(delete, &line, &[], b"").status, 403);
+        assert_eq!(
+            send(delete, &line, &["X-Delete-Token: wrong"], b"").status,
+            403
+        );
+        let token = format!("X-Delete-Token: {token}");
+        assert_eq!(send(delete, &line, &[&token], b"").status, 200);
+        assert_eq!(download(&link, &[]).status, 410);
+        assert_eq!(send(delete, &line, &[&token], b"").status, 404);
+    }
+
+    #[test]
+    fn targets_without_a_slash_are_refused() {
+        assert_eq!(send(delete, "DELETE ", &[], b"").status, 404);
+        assert_eq!(send(delete, "DELETE é", &[], b"").status, 404);
+        assert_eq!(send(put, "PUT ", &[], b"hello").status, 400);
+        assert_eq!(send(put, "PUT é", &[], b"hello").status, 400);
+    }
+
+    #[test]
+    fn head_matches_get() {
+        let link = link(&upload("head.txt", b"hello", &[]));
+        let got = download(&link, &[]);
+        let head = send(get, &format!("HEAD {link}"), &[], b"");
+        assert_eq!(head.status, 200);
+        assert!(head.body.is_empty());
+        for header in ["Content-Length", "Content-Type", "ETag", "Last-Modified"] {
+            assert_eq!(head.header(header), got.header(header), "{header}");
+        }
+        assert_eq!(head.header("Content-Length"), Some("5"));
+        let missing = send(get, "HEAD /missing/head.txt", &[], b"");
+        assert_eq!(missing.status, 410);
+        assert!(missing.body.is_empty());
+    }
+
+    #[test]
+    fn options_lists_the_methods() {
+        let response = send(options, "OPTIONS *", &[], b"");
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("Allow"), Some(ALLOWED_METHODS));
+        assert!(response.body.is_empty());
+    }
+
+    #[test]
+    fn multipart_post() {
+        let body = b"--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.txt\"\r\n\r\nfrom a form\r\n--b--\r\n";
+        let response = send(
+            post,
+            "POST /",
+            &["Content-Type: multipart/form-data; boundary=b"],
+            body,
+        );
+        assert_eq!(
+            response.status,
+            200,
+            "{}",
+            String::from_utf8_lossy(&response.body)
+        );
+        let response = download(&link(&response), &[]);
+        assert_eq!(response.body, b"from a form");
+    }
+
+    #[test]
+    fn links_are_encoded_and_escaped() {
+        let uploaded = upload("x%20y%3F%23%C3%A9.txt", b"hello", &[]);
+        let link = link(&uploaded);
+        assert!(link.ends_with("/x%20y%3F%23%C3%A9.txt"), "{link}");
+        assert_eq!(download(&link, &[]).body, b"hello");
+        let body = b"--b\r\nContent-Disposition: form-data; name=\"file\"; filename=\"<b>\"\r\n\r\nhi\r\n--b--\r\n";
+        let response = send(
+            post,
+            "POST /",
+            &[
+                "Content-Type: multipart/form-data; boundary=b",
+                "Accept: text/html",
+            ],
+            body,
+        );
+        let page = String::from_utf8_lossy(&response.body);
+        assert!(!page.contains("<b>"), "{page}");
+        assert!(page.contains("/%3Cb%3E\">"), "{page}");
+        assert_eq!(
+            html_escape("<a href=\"x\">'&'</a>"),
+            "&lt;a href=&quot;x&quot;&gt;&#39;&amp;&#39;&lt;/a&gt;"
+        );
+    }
+
+    #[test]
+    fn random_ids_use_the_alphabet() {
+        for (length, alphabet) in [(1, "ab"), (8, "0123456789"), (100, "xyz_-")] {
+            let id = random_id(length, alphabet.as_bytes()).unwrap();
+            assert_eq!(id.len(), length);
+            assert!(id.chars().all(|char| alphabet.contains(char)), "{id}");
+        }
+        // Every character turns up, so none are skipped by the rejection sampling
+        let id = random_id(1000, b"abcdefg").unwrap();
+        assert!("abcdefg".chars().all(|char| id.contains(char)), "{id}");
+    }
+
+    #[test]
+    fn taken_ids_are_retried() {
+        setup();
+        let metadata = Metadata::new("collision.txt", Duration::from_secs(60));
+        storage::get().create("taken-009", &metadata).unwrap();
+        let mut ids = ["taken-009", RESUMABLE_PATH, "fresh-009"].into_iter();
+        let id = create_upload_with(&metadata, || Ok(ids.next().unwrap().to_owned()));
+        assert_eq!(id.unwrap(), "fresh-009");
+        let mut attempts = 0;
+        let err = create_upload_with(&metadata, || {
+            attempts += 1;
+            Ok("taken-009".to_owned())
+        })
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(attempts, UPLOAD_ID_ATTEMPTS);
+    }
+
+    #[test]
+    fn expiry_is_capped_and_reported() {
+        setup();
+        let gc = &Config::get().gc;
+        for (name, headers, lifetime) in [
+            ("expiry.txt", &[][..], gc.lifetime_secs),
+            ("expiry.txt?expires=30", &[], 30),
+            ("expiry.txt", &["X-Expires-In: 45"], 45),
+            ("expiry.txt?expires=1000000000", &[], gc.max_lifetime_secs),
+            (
+                "expiry.txt",
+                &["X-Expires-In: 1000000000"],
+                gc.max_lifetime_secs,
+            ),
+        ] {
+            let uploaded = upload(name, b"hello", headers);
+            let link = link(&uploaded);
+            assert!(link.ends_with("/expiry.txt"), "{link}");
+            let metadata = storage::get().stat(link_id(&link)).unwrap();
+            assert_eq!(metadata.expires - metadata.created, lifetime, "{name}");
+            assert_eq!(
+                uploaded.header("X-Expires-At"),
+                Some(http_date(metadata.expires_at()).as_str())
+            );
+        }
+        for expires in ["0", "-5", "soon"] {
+            let response = send(
+                put,
+                "PUT /expiry.txt",
+                &[&format!("X-Expires-In: {expires}")],
+                b"hello",
+            );
+            assert_eq!(response.status, 400, "{expires}");
+        }
+    }
+
+    #[test]
+    fn expired_uploads_are_refused() {
+        let link = link(&upload("expired.txt", b"hello", &[]));
+        let id = link_id(&link);
+        assert_eq!(download(&link, &[]).status, 200);
+        let mut metadata = storage::get().stat(id).unwrap();
+        metadata.expires = metadata.created.saturating_sub(1);
+        storage::get().set_metadata(id, &metadata).unwrap();
+        assert_eq!(download(&link, &[]).status, 410);
+        assert_eq!(send(get, &format!("HEAD {link}"), &[], b"").status, 410);
+    }
+
+    #[test]
+    fn download_limits() {
+        let uploaded = upload("limited.txt", b"hello", &["X-Max-Downloads: 2"]);
+        let link = link(&uploaded);
+        let id = link_id(&link).to_owned();
+        // A limited upload is always sent whole, so ranges cant stretch the limit
+        let response = download(&link, &["Range: bytes=1-"]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+        assert_eq!(storage::get().stat(&id).unwrap().downloads, 1);
+        let head = send(get, &format!("HEAD {link}"), &[], b"");
+        assert_eq!(head.status, 200);
+        assert_eq!(storage::get().stat(&id).unwrap().downloads, 1);
+        let last = download(&link, &[]);
+        assert_eq!(last.status, 200);
+        assert_eq!(last.body, b"hello");
+        // Deleted once the last download was sent
+        assert_eq!(
+            storage::get().stat(&id).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        assert!(!storage::is_sending(&id));
+        assert_eq!(download(&link, &[]).status, 410);
+    }
+
+    #[test]
+    fn rebuilt_uploads_are_refused() {
+        let link = link(&upload("rebuilt.txt", b"hello", &[]));
+        let id = link_id(&link);
+        let mut metadata = storage::get().stat(id).unwrap();
+        metadata.rebuilt = true;
+        storage::get().set_metadata(id, &metadata).unwrap();
+        assert_eq!(download(&link, &[]).status, 410);
+        assert!(storage::get().stat(id).is_ok()); // Kept until it expires
+    }
+
+    #[test]
+    fn put_then_get() {
+        let uploaded = upload("hello.txt", b"hello", &[]);
+        assert!(uploaded.header("X-Delete-Token").is_some());
+        let link = link(&uploaded);
+        assert!(link.ends_with("/hello.txt"), "{link}");
+        let response = download(&link, &[]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+        assert_eq!(response.header("Content-Length"), Some("5"));
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        // The file name is part of the link
+        let wrong_name = link.replace("hello.txt", "other.txt");
+        assert_eq!(download(&wrong_name, &[]).status, 410);
+        assert_eq!(download("/missing/hello.txt", &[]).status, 410);
+        // Nothing outside an upload can be fetched through it
+        assert_eq!(download("/../hello.txt", &[]).status, 410);
+        let response = send(put, "PUT /.hidden", &[], b"hello");
+        assert_eq!(response.status, 403);
+    }
+
+    /// The SHA-256 digest of `hello`.
+    const HELLO_SHA256: &str = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+
+    /// Whether any upload is named `name`.
+    fn uploaded_as(name: &str) -> bool {
+        let storage = storage::get();
+        storage.list().unwrap().iter().any(|id| {
+            storage
+                .stat(id)
+                .is_ok_and(|metadata| metadata.file_name == name)
+        })
+    }
+
+    #[test]
+    fn mismatched_digests_are_refused() {
+        let digest = format!("Digest: {}", checksum::digest_header(HELLO_SHA256).unwrap());
+        let md5 = "Content-MD5: XUFAKrxLKna5cZ2REBfFkg==";
+        let content_digest =
+            "Content-Digest: sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:";
+        for (name, header) in [
+            ("digest-018.txt", digest.as_str()),
+            ("md5-018.txt", md5),
+            ("content-digest-018.txt", content_digest),
+        ] {
+            let line = format!("PUT /{name}");
+            let response = send(put, &line, &[header], b"jello");
+            assert_eq!(response.status, 400, "{header}");
+            assert!(!uploaded_as(name), "{header}");
+            // The same digest is fine for what it is of
+            let link = link(&upload(name, b"hello", &[header]));
+            assert_eq!(download(&link, &[]).body, b"hello");
+        }
+        let response = send(put, "PUT /bad-018.txt", &["Content-MD5: short"], b"hello");
+        assert_eq!(response.status, 400);
+        assert!(!uploaded_as("bad-018.txt"));
+    }
+
+    #[test]
+    fn digests_are_reported() {
+        let uploaded = upload("digest.txt", b"hello", &[]);
+        assert_eq!(uploaded.header("X-Sha256"), Some(HELLO_SHA256));
+        let response = download(&link(&uploaded), &[]);
+        assert_eq!(
+            response.header("Digest"),
+            Some("SHA-256=LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=")
+        );
+        assert_eq!(
+            response.header("ETag"),
+            Some(format!("\"{HELLO_SHA256}\"").as_str())
+        );
+    }
+
+    #[test]
+    fn passwords() {
+        // An empty password is no password
+        let open = link(&upload("open.txt", b"hello", &["X-Upload-Password: "]));
+        assert_eq!(download(&open, &[]).status, 200);
+        let link = link(&upload(
+            "secret.txt",
+            b"hello",
+            &["X-Upload-Password: hunter2"],
+        ));
+        let hash = storage::get().stat(link_id(&link)).unwrap().password_hash;
+        assert!(hash.is_some_and(|hash| !hash.contains("hunter2")));
+        let response = download(&link, &[]);
+        assert_eq!(response.status, 401);
+        assert!(response.header("WWW-Authenticate").is_some());
+        assert_ne!(response.body, b"hello");
+        assert_eq!(send(get, &format!("HEAD {link}"), &[], b"").status, 401);
+        assert_eq!(download(&format!("{link}?password=wrong"), &[]).status, 401);
+        let response = download(&format!("{link}?password=hunter2"), &[]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+        // Basic auth, the user name is ignored
+        let response = download(&link, &["Authorization: Basic dXNlcjpodW50ZXIy"]);
+        assert_eq!(response.status, 200);
+        let response = download(&link, &["Authorization: Basic dXNlcjp3cm9uZw=="]);
+        assert_eq!(response.status, 401);
+    }
+
+    #[test]
+    fn resumable_upload() {
+        let created = send(
+            post,
+            "POST /",
+            &[
+                "Tus-Resumable: 1.0.0",
+                "Upload-Length: 11",
+                "Upload-Metadata: filename dHVzLnR4dA==", // tus.txt
+            ],
+            b"",
+        );
+        assert_eq!(
+            created.status,
+            201,
+            "{}",
+            String::from_utf8_lossy(&created.body)
+        );
+        let location = link(&created);
+        assert!(location.starts_with("/resumable/"), "{location}");
+        assert!(created
+            .header("Location")
+            .is_some_and(|header| header.ends_with(&location)));
+        let chunk = |offset: &str, body: &[u8]| {
+            let offset = format!("Upload-Offset: {offset}");
+            send(
+                patch,
+                &format!("PATCH {location}"),
+                &[
+                    "Tus-Resumable: 1.0.0",
+                    "Content-Type: application/offset+octet-stream",
+                    &offset,
+                ],
+                body,
+            )
+        };
+        let response = chunk("0", b"hello ");
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("Upload-Offset"), Some("6"));
+        let progress = send(get, &format!("HEAD {location}"), &[], b"");
+        assert_eq!(progress.header("Upload-Offset"), Some("6"));
+        assert_eq!(progress.header("Upload-Length"), Some("11"));
+        // Chunks have to carry on from where the last one stopped
+        assert_eq!(chunk("0", b"hello ").status, 409);
+        let finished = chunk("6", b"world");
+        assert_eq!(
+            finished.status,
+            200,
+            "{}",
+            String::from_utf8_lossy(&finished.body)
+        );
+        assert!(finished.header("X-Delete-Token").is_some());
+        let link = link(&finished);
+        assert!(link.ends_with("/tus.txt"), "{link}");
+        assert_eq!(download(&link, &[]).body, b"hello world");
+        // Gone once it is stored
+        assert_eq!(chunk("11", b"").status, 404);
+    }
+
+    #[test]
+    fn ranges() {
+        let link = link(&upload("ranges.txt", b"hello", &[]));
+        let response = download(&link, &["Range: bytes=1-3"]);
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, b"ell");
+        assert_eq!(response.header("Content-Range"), Some("bytes 1-3/5"));
+        let response = download(&link, &["Range: bytes=0-0,-1"]);
+        assert_eq!(response.status, 206);
+        let content_type = response.header("Content-Type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let body = String::from_utf8_lossy(&response.body);
+        assert!(
+            body.contains("Content-Range: bytes 0-0/5\r\n\r\nh\r\n"),
+            "{body}"
+        );
+        assert!(
+            body.contains("Content-Range: bytes 4-4/5\r\n\r\no\r\n"),
+            "{body}"
+        );
+        assert!(body.ends_with(&format!("--{boundary}--\r\n")), "{body}");
+        let response = download(&link, &["Range: bytes=10-"]);
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("Content-Range"), Some("bytes */5"));
+        // A range of a file which has changed gets the whole file
+        let response = download(&link, &["Range: bytes=1-3", "If-Range: \"other\""]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, b"hello");
+        let etag = format!("If-Range: {}", response.header("ETag").unwrap());
+        let response = download(&link, &["Range: bytes=1-3", &etag]);
+        assert_eq!(response.status, 206);
+        assert_eq!(response.body, b"ell");
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let link = link(&upload("conditional.txt", b"hello", &[]));
+        let response = download(&link, &[]);
+        let etag = response.header("ETag").unwrap();
+        let last_modified = response.header("Last-Modified").unwrap();
+        let not_modified = download(&link, &[&format!("If-None-Match: {etag}")]);
+        assert_eq!(not_modified.status, 304);
+        assert!(not_modified.body.is_empty());
+        assert_eq!(not_modified.header("ETag"), Some(etag));
+        let not_modified = download(&link, &[&format!("If-Modified-Since: {last_modified}")]);
+        assert_eq!(not_modified.status, 304);
+        assert_eq!(download(&link, &["If-None-Match: \"other\""]).status, 200);
+        assert_eq!(download(&link, &["If-Match: \"other\""]).status, 412);
+        assert_eq!(download(&link, &[&format!("If-Match: {etag}")]).status, 200);
+    }
+
+    #[test]
+    fn upload_cache_control_is_private() {
+        assert_eq!(
+            upload_cache_control(Some("no-cache"), false),
+            "private, no-cache"
+        );
+        assert_eq!(
+            upload_cache_control(Some("public, max-age=60, s-maxage=600"), false),
+            "private, max-age=60"
+        );
+        assert_eq!(upload_cache_control(Some("private"), false), "private");
+        assert_eq!(upload_cache_control(None, false), "private");
+        assert_eq!(
+            upload_cache_control(Some("max-age=60"), true),
+            "private, no-store"
+        );
+        let cache_control = |headers: &[&str], query: &str| {
+            let link = link(&upload("cached.txt", b"hello", headers));
+            let response = download(&format!("{link}{query}"), &[]);
+            assert_eq!(response.status, 200, "{headers:?}");
+            response.header("Cache-Control").map(str::to_owned)
+        };
+        assert_eq!(cache_control(&[], "").as_deref(), Some("private, no-cache"));
+        assert_eq!(
+            cache_control(&["X-Max-Downloads: 5"], "").as_deref(),
+            Some("private, no-store")
+        );
+        assert_eq!(
+            cache_control(&["X-Upload-Password: hunter2"], "?password=hunter2").as_deref(),
+            Some("private, no-store")
+        );
+    }
+
+    #[test]
+    fn content_types() {
+        let content_type = |name: &str, body: &[u8], headers: &[&str]| {
+            let response = download(&link(&upload(name, body, headers)), &[]);
+            assert_eq!(response.header("X-Content-Type-Options"), Some("nosniff"));
+            response.header("Content-Type").map(str::to_owned)
+        };
+        // What the uploader declared comes first, then the name, then the contents
+        assert_eq!(
+            content_type("photo.bin", b"hello", &["Content-Type: image/png"]).as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(
+            content_type(
+                "style.css",
+                b"hello",
+                &["Content-Type: application/octet-stream"]
+            )
+            .as_deref(),
+            Some("text/css; charset=utf-8")
+        );
+        assert_eq!(
+            content_type("picture", b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", &[]).as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(
+            content_type("blob", b"\0\x01\x02", &[]).as_deref(),
+            Some("application/octet-stream")
+        );
+    }
+
+    #[test]
+    fn upload_dispositions() {
+        setup();
+        let disposition = |query: &str, content_type: &str| {
+            let mut packet =
+                HttpRequest::from_head(format!("GET /abc/a{query} HTTP/1.1\r\n\r\n").into_bytes());
+            upload_disposition(&mut packet, content_type)
+        };
+        assert!(matches!(disposition("", "image/png"), Disposition::Inline));
+        assert!(matches!(
+            disposition("", "text/plain; charset=utf-8"),
+            Disposition::Inline
+        ));
+        assert!(matches!(
+            disposition("", "text/html"),
+            Disposition::Attachment
+        ));
+        assert!(matches!(
+            disposition("", "application/zip"),
+            Disposition::Attachment
+        ));
+        assert!(matches!(
+            disposition("?download", "image/png"),
+            Disposition::Attachment
+        ));
+        // Only text can be shown as plain text
+        assert!(matches!(
+            disposition("?inline", "text/html"),
+            Disposition::InlineAsText
+        ));
+        assert!(matches!(
+            disposition("?inline", "application/zip"),
+            Disposition::Attachment
+        ));
+        assert!(matches!(
+            disposition("?inline&download", "text/html"),
+            Disposition::Attachment
+        ));
+    }
+
+    #[test]
+    fn file_names_are_quoted_and_encoded() {
+        assert_eq!(
+            content_disposition(&Disposition::Attachment, "a b.txt"),
+            "attachment; filename=\"a b.txt\"; filename*=UTF-8''a%20b.txt"
+        );
+        assert_eq!(
+            content_disposition(&Disposition::Inline, "say \"hi\"\\100%.txt"),
+            "inline; filename=\"say _hi__100_.txt\"; filename*=UTF-8''say%20%22hi%22%5C100%25.txt"
+        );
+        assert_eq!(
+            content_disposition(&Disposition::InlineAsText, "caf\u{e9};\r\n.txt"),
+            "inline; filename=\"caf_;__.txt\"; filename*=UTF-8''caf%C3%A9%3B%0D%0A.txt"
+        );
+    }
+
+    #[test]
+    fn downloads_are_attachments_unless_safe() {
+        let page = link(&upload("page.html", b"<script>alert(1)</script>", &[]));
+        let response = download(&page, &[]);
+        assert!(response
+            .header("Content-Disposition")
+            .is_some_and(|header| header.starts_with("attachment; filename=\"page.html\"")));
+        let response = download(&format!("{page}?inline"), &[]);
+        assert!(response
+            .header("Content-Disposition")
+            .is_some_and(|header| header.starts_with("inline;")));
+        assert_eq!(
+            response.header("Content-Type"),
+            Some("text/plain; charset=utf-8")
+        );
+        let photo = link(&upload("photo.png", b"\x89PNG\r\n\x1a\n", &[]));
+        let response = download(&photo, &[]);
+        assert!(response
+            .header("Content-Disposition")
+            .is_some_and(|header| header.starts_with("inline;")));
+        assert_eq!(response.header("Content-Type"), Some("image/png"));
+        let response = download(&format!("{photo}?download"), &[]);
+        assert!(response
+            .header("Content-Disposition")
+            .is_some_and(|header| header.starts_with("attachment;")));
     }
 }
@@ -1,4 +1,5 @@
 use std::{
+    borrow::Cow,
     collections::HashMap,
     fmt::Display,
     io::{Read, Write},
@@ -8,6 +9,12 @@ use std::{
 
 use crate::log;
 
+/// Headers whose values are secrets, which are left out of the log.
+const SECRET_HEADERS: &[&str] = &["X-Upload-Key", "X-Upload-Password", "Authorization"];
+/// Query parameters whose values are secrets, which are left out of the log.
+const SECRET_PARAMS: &[&str] = &["key", "password"];
+const REDACTED: &str = "(REDACTED)";
+
 enum PacketSeparatorState {
     None,
     FirstReturn,
@@ -60,18 +67,49 @@ impl PacketSeparatorState {
 pub struct HttpRequest {
     method_line: Option<String>,
     headers: Option<HashMap<String, String>>,
-    stream: TcpStream,
+    stream: RequestStream,
+    framing: Option<Framing>,
+    trailers: HashMap<String, String>,
+    /// The heads of the responses sent, for the log. Bodies are left out, as they can hold links with secrets in.
     response: Vec<u8>,
     buf_full: bool,
+    close: bool,
+    head_sent: bool,
+    /// Whether the client has already been told to send its body with `100 Continue`.
+    continued: bool,
 }
 impl HttpRequest {
     pub fn new(client: TcpStream) -> Self {
+        Self::with_buffered(client, None)
+    }
+    /// Creates a request whose first bytes have already been read off `client` into `buffered`, which is read from before the connection itself.
+    pub fn with_buffered(client: TcpStream, buffered: Option<Box<dyn Read + Send>>) -> Self {
+        Self::from_stream(RequestStream {
+            buffered,
+            tcp: Some(client),
+        })
+    }
+    /// Creates a request from a head read ahead of time with no connection behind it, so it can be looked over before its body arrives. Its body reads as empty and replies go nowhere.
+    pub fn from_head(head: Vec<u8>) -> Self {
+        let mut packet = Self::from_stream(RequestStream {
+            buffered: Some(Box::new(std::io::Cursor::new(head))),
+            tcp: None,
+        });
+        packet.close = true; // Theres no connection to keep, or body to skip
+        packet
+    }
+    fn from_stream(stream: RequestStream) -> Self {
         Self {
             method_line: None,
             headers: None,
-            stream: client,
+            stream,
+            framing: None,
+            trailers: HashMap::new(),
             response: Vec::new(),
             buf_full: false,
+            close: false,
+            head_sent: false,
+            continued: false,
         }
     }
     pub fn method(&mut self) -> Option<String> {
@@ -100,7 +138,8 @@ impl HttpRequest {
             }
         }
     }
-    pub fn path(&mut self) -> Option<String> {
+    /// The request target, including any query string.
+    fn target(&mut self) -> Option<String> {
         match self.method_line {
             Some(ref method_line) => {
                 Some(method_line.split_once(" ")?.1.split_once(" ")?.0.to_owned())
@@ -124,10 +163,29 @@ impl HttpRequest {
                     }
                 }
                 self.method_line = String::from_utf8(bytes).ok();
-                self.path()
+                self.target()
             }
         }
     }
+    /// The request target without its query string.
+    pub fn path(&mut self) -> Option<String> {
+        let target = self.target()?;
+        Some(
+            target
+                .split_once('?')
+                .map_or(target.as_str(), |(path, _)| path)
+                .to_owned(),
+        )
+    }
+    /// Finds a parameter in the query string, e.g. `Some("60")` for `expires` in `/file.txt?expires=60`. Parameters without a value, such as `?download`, give an empty string.
+    pub fn query(&mut self, name: &str) -> Option<String> {
+        let target = self.target()?;
+        let (_, query) = target.split_once('?')?;
+        query.split('&').find_map(|param| {
+            let (key, value) = param.split_once('=').unwrap_or((param, ""));
+            (key == name).then(|| value.to_owned())
+        })
+    }
     pub fn protocol(&mut self) -> Option<String> {
         match self.method_line {
             Some(ref method_line) => {
@@ -171,7 +229,7 @@ impl HttpRequest {
             Some(ref headers) => Some(headers),
             None => {
                 let mut header_data: Vec<u8> = Vec::new();
-                let mut state = PacketSeparatorState::None;
+                let mut state = PacketSeparatorState::FirstNewline; // The method line's CRLF has already been read
                 loop {
                     let mut byte = [0u8];
                     match self.stream.read(&mut byte) {
@@ -195,8 +253,20 @@ impl HttpRequest {
                     let mut split_headers = HashMap::new();
                     let header_lines = str_headers.lines();
                     for line in header_lines {
-                        if let Some((header, value)) = line.split_once(": ") {
-                            split_headers.insert(header.to_owned(), value.to_owned());
+                        if let Some((header, value)) = line.split_once(':') {
+                            let (header, value) = (header.trim(), value.trim());
+                            // A repeated field means the same as one listing each value, so neither is lost
+                            let repeated = split_headers.iter_mut().find(
+                                |(known, _): &(&String, &mut String)| {
+                                    known.eq_ignore_ascii_case(header)
+                                },
+                            );
+                            if let Some((_, values)) = repeated {
+                                values.push_str(", ");
+                                values.push_str(value);
+                            } else {
+                                split_headers.insert(header.to_owned(), value.to_owned());
+                            }
                         }
                     }
                     self.headers = Some(split_headers);
@@ -207,60 +277,469 @@ impl HttpRequest {
             }
         }
     }
-    pub fn body_stream(&mut self) -> &mut TcpStream {
+    /// Looks up a header by name, ignoring ASCII case as header names are case-insensitive.
+    pub fn header(&mut self, name: &str) -> Option<&str> {
+        self.headers()?
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.trim())
+    }
+    /// Returns the parsed `Content-Length` header, `None` if it is absent and `Some(Err(_))` if it is not a valid length. A length sent more than once is only valid if every copy agrees, see RFC 9112 section 6.3.
+    pub fn content_length(&mut self) -> Option<std::io::Result<u64>> {
+        let mut lengths = self
+            .header("Content-Length")?
+            .split(',')
+            .map(|length| length.trim().parse::<u64>());
+        let length = lengths.next()?;
+        Some(match length {
+            Ok(length) if lengths.all(|other| other == Ok(length)) => Ok(length),
+            Ok(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Conflicting Content-Length values",
+            )),
+            Err(err) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Invalid Content-Length: {err}"),
+            )),
+        })
+    }
+    /// Returns a reader over the request body with any transfer framing removed, reading no further than the end of the body.
+    /// # Errors
+    /// Returns an `InvalidData` error if the body has an invalid or conflicting `Content-Length`, or both a `Content-Length` and a `Transfer-Encoding`, or an `Unsupported` error if it has a `Transfer-Encoding` other than chunked.
+    pub fn body(&mut self) -> std::io::Result<Body<'_>> {
+        if self.framing.is_none() {
+            self.framing = Some(self.framing()?);
+        }
+        Ok(match self.framing.as_mut() {
+            Some(Framing::Chunked(state)) => Body::Chunked(ChunkedReader {
+                stream: &mut self.stream,
+                trailers: &mut self.trailers,
+                state,
+            }),
+            Some(Framing::Fixed(remaining)) => Body::Fixed {
+                stream: &mut self.stream,
+                remaining,
+            },
+            Some(Framing::None) | None => Body::Empty,
+        })
+    }
+    /// Works out how the body is framed from the headers.
+    fn framing(&mut self) -> std::io::Result<Framing> {
+        let chunked = match self.header("Transfer-Encoding") {
+            Some(encoding) => {
+                // Chunked must be the final encoding, and we dont support any others (e.g. gzip)
+                if !encoding.eq_ignore_ascii_case("chunked") {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!("Unsupported Transfer-Encoding \"{encoding}\""),
+                    ));
+                }
+                true
+            }
+            None => false,
+        };
+        let length = self.content_length().transpose()?;
+        // The two could be read differently by a proxy in front, letting a request be hidden in the body
+        if chunked && length.is_some() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "Both Transfer-Encoding and Content-Length given",
+            ));
+        }
+        Ok(if chunked {
+            // Transfer-Encoding overrides Content-Length
+            Framing::Chunked(ChunkState::Size)
+        } else if let Some(length) = length {
+            Framing::Fixed(length)
+        } else {
+            Framing::None
+        })
+    }
+    /// The trailer fields sent after a chunked body, only populated once the body has been read to the end.
+    pub fn trailers(&self) -> &HashMap<String, String> {
+        &self.trailers
+    }
+    /// The client connection, or `None` for a request made with [`HttpRequest::from_head`].
+    pub fn body_stream(&mut self) -> Option<&mut TcpStream> {
         if self.method_line.is_none() {
             self.method();
         }
         if self.headers.is_none() {
             self.headers();
         }
-        &mut self.stream
+        self.stream.tcp.as_mut()
     }
-    const MAX_BUFFER_SIZE: usize = 500;
-    pub fn respond_string(&mut self, data: &str) -> std::io::Result<()> {
-        if !self.buf_full {
-            for byte in data.as_bytes() {
+    /// Whether the client wants the connection kept open after this request. HTTP/1.1 connections are persistent unless the client sends `Connection: close`, older protocols must opt in with `Connection: keep-alive`.
+    pub fn keep_alive(&mut self) -> bool {
+        if self.close {
+            return false;
+        }
+        let protocol = self.protocol();
+        let connection = self.header("Connection").map(str::to_ascii_lowercase);
+        let tokens = connection.as_deref().unwrap_or("");
+        match protocol.as_deref() {
+            Some("HTTP/1.1") => !tokens.split(',').any(|token| token.trim() == "close"),
+            Some("HTTP/1.0") => tokens.split(',').any(|token| token.trim() == "keep-alive"),
+            _ => false,
+        }
+    }
+    /// Whether the client is waiting for `100 Continue` before sending its body, and hasnt been sent it yet.
+    pub fn expects_continue(&mut self) -> bool {
+        !self.continued
+            && self
+                .header("Expect")
+                .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"))
+    }
+    /// Notes that the client has been sent `100 Continue`, so it isnt sent again.
+    pub fn set_continued(&mut self) {
+        self.continued = true;
+    }
+    /// Marks the connection to be closed once this request is finished, responses sent afterwards include `Connection: close`.
+    pub fn close_connection(&mut self) {
+        self.close = true;
+    }
+    /// Sends the status line and headers of a response, followed by a `Content-Length` and the `Connection` header. If `content_length` is `None` the body is delimited by closing the connection.
+    pub fn respond_head(
+        &mut self,
+        status: &str,
+        headers: &[(&str, &str)],
+        content_length: Option<u64>,
+    ) -> std::io::Result<()> {
+        if content_length.is_none() || !self.keep_alive() {
+            self.close = true;
+        }
+        let mut head = format!("HTTP/1.1 {status}\r\n");
+        for (header, value) in headers {
+            head.push_str(&format!("{header}: {value}\r\n"));
+        }
+        if let Some(content_length) = content_length {
+            head.push_str(&format!("Content-Length: {content_length}\r\n"));
+        }
+        if self.close {
+            head.push_str("Connection: close\r\n");
+        } else if self.protocol().as_deref() == Some("HTTP/1.0") {
+            head.push_str("Connection: keep-alive\r\n");
+        }
+        head.push_str("\r\n");
+        self.log_response(&head);
+        self.respond_string(&head)?;
+        self.head_sent = true;
+        Ok(())
+    }
+    /// Keeps a response head for the log, with the values of secret headers left out.
+    fn log_response(&mut self, head: &str) {
+        for line in head.split_inclusive("\r\n") {
+            let line = match line.trim_end().split_once(": ") {
+                Some((header, value)) => {
+                    format!("{header}: {}\r\n", redact_header(header, value))
+                }
+                None => line.to_owned(),
+            };
+            for byte in line.bytes() {
                 if self.response.len() > Self::MAX_BUFFER_SIZE {
                     self.buf_full = true;
-                    break;
+                    return;
                 }
-                self.response.push(byte.to_owned());
+                self.response.push(byte);
             }
         }
-        self.stream.write_all(data.as_bytes())
+    }
+    /// Whether this is a `HEAD` request, whose responses are sent without a body.
+    pub fn is_head(&mut self) -> bool {
+        self.method()
+            .is_some_and(|method| method.eq_ignore_ascii_case("HEAD"))
+    }
+    /// Whether data sent now would be part of a body which shouldnt be sent, as this is a `HEAD` request.
+    fn body_suppressed(&mut self) -> bool {
+        self.head_sent && self.is_head()
+    }
+    /// Sends a complete response with a text body, which is sent as plain text unless `headers` has a `Content-Type`.
+    pub fn respond(
+        &mut self,
+        status: &str,
+        headers: &[(&str, &str)],
+        body: &str,
+    ) -> std::io::Result<()> {
+        let mut headers = headers.to_vec();
+        if !body.is_empty()
+            && !headers
+                .iter()
+                .any(|(header, _)| header.eq_ignore_ascii_case("Content-Type"))
+        {
+            headers.push(("Content-Type", "text/plain; charset=utf-8"));
+        }
+        self.respond_head(status, &headers, Some(body.len() as u64))?;
+        self.respond_string(body)
+    }
+    const MAX_BUFFER_SIZE: usize = 500;
+    pub fn respond_string(&mut self, data: &str) -> std::io::Result<()> {
+        if self.body_suppressed() {
+            return Ok(());
+        }
+        self.stream.tcp()?.write_all(data.as_bytes())
     }
     pub fn respond_data(&mut self, data: &[u8]) -> std::io::Result<()> {
-        if !self.buf_full {
-            for byte in data {
-                if self.response.len() > Self::MAX_BUFFER_SIZE {
-                    self.buf_full = true;
-                    break;
-                }
-                self.response.push(byte.to_owned());
-            }
+        if self.body_suppressed() {
+            return Ok(());
         }
-        self.stream.write_all(data)
+        self.stream.tcp()?.write_all(data)
     }
     pub fn read_all(&mut self) -> Option<()> {
         self.headers()?;
         Some(())
     }
 }
+/// The client connection, read from after any part of the request which was buffered ahead of time.
+pub struct RequestStream {
+    buffered: Option<Box<dyn Read + Send>>,
+    tcp: Option<TcpStream>,
+}
+impl RequestStream {
+    fn tcp(&mut self) -> std::io::Result<&mut TcpStream> {
+        self.tcp
+            .as_mut()
+            .ok_or_else(|| std::io::ErrorKind::NotConnected.into())
+    }
+}
+impl Read for RequestStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if let Some(buffered) = self.buffered.as_mut() {
+            let bytes_read = buffered.read(buf)?;
+            if bytes_read > 0 || buf.is_empty() {
+                return Ok(bytes_read);
+            }
+            self.buffered = None;
+        }
+        match self.tcp.as_mut() {
+            Some(tcp) => tcp.read(buf),
+            None => Ok(0),
+        }
+    }
+}
+/// How the end of a request body is found, along with how much of it has been read so far.
+enum Framing {
+    None,
+    Fixed(u64),
+    Chunked(ChunkState),
+}
+/// A request body, see [`HttpRequest::body`].
+pub enum Body<'a> {
+    /// The request has no body.
+    Empty,
+    /// A body framed by `Content-Length`.
+    Fixed {
+        stream: &'a mut RequestStream,
+        remaining: &'a mut u64,
+    },
+    /// A body framed by `Transfer-Encoding: chunked`.
+    Chunked(ChunkedReader<'a>),
+}
+impl Body<'_> {
+    /// The number of bytes left to read, if it is known ahead of time.
+    pub fn length(&self) -> Option<u64> {
+        match self {
+            Body::Empty => Some(0),
+            Body::Fixed { remaining, .. } => Some(**remaining),
+            Body::Chunked(_) => None,
+        }
+    }
+}
+impl Read for Body<'_> {
+    /// Reads the decoded body, returning an `UnexpectedEof` error if the connection closes before the body is complete.
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Body::Empty => Ok(0),
+            Body::Fixed { stream, remaining } => {
+                if **remaining == 0 || buf.is_empty() {
+                    return Ok(0);
+                }
+                let to_read = (**remaining).min(buf.len() as u64) as usize;
+                let bytes_read = stream.read(&mut buf[0..to_read])?;
+                if bytes_read == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                **remaining -= bytes_read as u64;
+                Ok(bytes_read)
+            }
+            Body::Chunked(reader) => reader.read(buf),
+        }
+    }
+}
+enum ChunkState {
+    /// Expecting a chunk size line.
+    Size,
+    /// Inside a chunk with this many bytes left.
+    Data(u64),
+    /// Expecting the CRLF after a chunk's data.
+    DataEnd,
+    /// Past the last chunk, and the trailers have been read.
+    Done,
+}
+/// Decodes a `Transfer-Encoding: chunked` body, ignoring chunk extensions and collecting trailers.
+pub struct ChunkedReader<'a> {
+    stream: &'a mut RequestStream,
+    trailers: &'a mut HashMap<String, String>,
+    state: &'a mut ChunkState,
+}
+impl ChunkedReader<'_> {
+    /// The longest chunk size or trailer line accepted, to stop a client sending an endless line.
+    const MAX_LINE_LENGTH: usize = 4096;
+    /// Reads a single CRLF terminated line one byte at a time, so nothing past the line is consumed.
+    fn read_line(&mut self) -> std::io::Result<String> {
+        let mut bytes = Vec::new();
+        loop {
+            let mut byte = [0u8];
+            if self.stream.read(&mut byte)? == 0 {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            bytes.push(byte[0]);
+            if bytes.ends_with(b"\r\n") {
+                bytes.truncate(bytes.len() - 2);
+                break;
+            }
+            if bytes.len() > Self::MAX_LINE_LENGTH {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "Chunk line too long",
+                ));
+            }
+        }
+        String::from_utf8(bytes)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+    fn read_trailers(&mut self) -> std::io::Result<()> {
+        loop {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                return Ok(());
+            }
+            if let Some((header, value)) = line.split_once(':') {
+                self.trailers
+                    .insert(header.trim().to_owned(), value.trim().to_owned());
+            }
+        }
+    }
+}
+impl Read for ChunkedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            match *self.state {
+                ChunkState::Size => {
+                    let line = self.read_line()?;
+                    let size = line.split_once(';').map_or(line.as_str(), |(size, _)| size); // Drop chunk extensions
+                    let size = u64::from_str_radix(size.trim(), 16).map_err(|err| {
+                        std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("Invalid chunk size \"{size}\": {err}"),
+                        )
+                    })?;
+                    if size == 0 {
+                        self.read_trailers()?;
+                        *self.state = ChunkState::Done;
+                    } else {
+                        *self.state = ChunkState::Data(size);
+                    }
+                }
+                ChunkState::Data(remaining) => {
+                    if buf.is_empty() {
+                        return Ok(0);
+                    }
+                    let to_read = remaining.min(buf.len() as u64) as usize;
+                    let bytes_read = self.stream.read(&mut buf[0..to_read])?;
+                    if bytes_read == 0 {
+                        return Err(std::io::ErrorKind::UnexpectedEof.into());
+                    }
+                    *self.state = if remaining == bytes_read as u64 {
+                        ChunkState::DataEnd
+                    } else {
+                        ChunkState::Data(remaining - bytes_read as u64)
+                    };
+                    return Ok(bytes_read);
+                }
+                ChunkState::DataEnd => {
+                    if !self.read_line()?.is_empty() {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            "Missing CRLF after chunk data",
+                        ));
+                    }
+                    *self.state = ChunkState::Size;
+                }
+                ChunkState::Done => return Ok(0),
+            }
+        }
+    }
+}
+impl HttpRequest {
+    /// The most unread body that will be skipped to keep a connection open, past this it is cheaper to reconnect.
+    const MAX_DRAIN_SIZE: u64 = 64 * 1024;
+}
+#[cfg(test)]
+impl HttpRequest {
+    /// A request read off a real connection which the client sends `raw` down and then stops writing to, along with the client's end, which the response can be read from.
+    pub fn from_bytes(raw: &[u8]) -> (Self, TcpStream) {
+        let listener =
+            std::net::TcpListener::bind("127.0.0.1:0").expect("Should bind a local port");
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        // Written from another thread, so a request bigger than the socket buffer cant block the test
+        let mut writer = client.try_clone().unwrap();
+        let raw = raw.to_vec();
+        std::thread::spawn(move || {
+            let _ = writer.write_all(&raw);
+            let _ = writer.shutdown(std::net::Shutdown::Write);
+        });
+        (Self::new(server), client)
+    }
+}
 impl Drop for HttpRequest {
+    /// Skips any of the body the handler didnt read, so the next request on the connection starts in the right place, or shuts the connection down if it shouldnt be reused.
     fn drop(&mut self) {
-        let _ = self.stream.read_to_end(&mut Vec::new());
-        let _ = self.stream.shutdown(std::net::Shutdown::Both);
+        if !self.close
+            && (self.method_line.is_none() || self.headers().is_none() || !self.keep_alive())
+        {
+            self.close = true;
+        }
+        if !self.close {
+            let drained = self.body().and_then(|body| {
+                std::io::copy(
+                    &mut body.take(Self::MAX_DRAIN_SIZE + 1),
+                    &mut std::io::sink(),
+                )
+            });
+            if !matches!(drained, Ok(bytes) if bytes <= Self::MAX_DRAIN_SIZE) {
+                self.close = true;
+            }
+        }
+        if self.close {
+            if let Some(tcp) = &self.stream.tcp {
+                let _ = tcp.shutdown(std::net::Shutdown::Both);
+            }
+        }
     }
 }
 impl Display for HttpRequest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "----- INCOMING -----\n")?;
-        write!(f, "< {}\r\n", self.method_line.as_ref().unwrap())?;
+        write!(
+            f,
+            "< {}\r\n",
+            redact_query(self.method_line.as_ref().unwrap())
+        )?;
         for (header, value) in self.headers.as_ref().expect("Headers were not calculated") {
-            write!(f, "< {}: {}\r\n", header, value)?;
+            write!(f, "< {}: {}\r\n", header, redact_header(header, value))?;
         }
         write!(f, "< \r\n")?;
         write!(f, "< (BODY NOT DISPLAYED FOR MEMORY PURPOSES)\r\n")?;
+        for (trailer, value) in self.trailers() {
+            write!(f, "< {}: {}\r\n", trailer, redact_header(trailer, value))?;
+        }
         let str_val = String::from_utf8_lossy(&self.response);
         write!(f, "----- OUTGOING -----\n")?;
         for (index, line) in str_val.lines().enumerate() {
@@ -276,3 +755,194 @@ impl Display for HttpRequest {
         Ok(())
     }
 }
+/// A header's value as it is logged, which is hidden if it is a secret. Links, as in `Location`, have their secret query parameters hidden.
+fn redact_header<'a>(header: &str, value: &'a str) -> Cow<'a, str> {
+    if SECRET_HEADERS
+        .iter()
+        .any(|secret| secret.eq_ignore_ascii_case(header))
+    {
+        Cow::Borrowed(REDACTED)
+    } else if header.eq_ignore_ascii_case("Location") {
+        Cow::Owned(redact_query(value))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+/// Hides the values of secret query parameters in a request line or link, the query running from the first `?` to the next space.
+fn redact_query(text: &str) -> String {
+    let Some((start, rest)) = text.split_once('?') else {
+        return text.to_owned();
+    };
+    let (query, end) = rest.split_at(rest.find(' ').unwrap_or(rest.len()));
+    let query: Vec<String> = query
+        .split('&')
+        .map(|param| match param.split_once('=') {
+            Some((name, _)) if SECRET_PARAMS.contains(&name) => format!("{name}={REDACTED}"),
+            _ => param.to_owned(),
+        })
+        .collect();
+    format!("{start}?{}{end}", query.join("&"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_body(raw: &[u8]) -> (HttpRequest, std::io::Result<Vec<u8>>) {
+        let (mut packet, _client) = HttpRequest::from_bytes(raw);
+        let mut body = Vec::new();
+        let result = packet
+            .body()
+            .and_then(|mut reader| reader.read_to_end(&mut body));
+        (packet, result.map(|_| body))
+    }
+
+    #[test]
+    fn fixed_body_stops_at_its_length() {
+        let (mut packet, _client) = HttpRequest::from_bytes(
+            b"PUT /a.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n",
+        );
+        let mut body = Vec::new();
+        packet.body().unwrap().read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"hello");
+        let mut rest = Vec::new();
+        packet.stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn cut_short_bodies_are_refused() {
+        let (_, body) = read_body(b"PUT /a.txt HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel");
+        assert_eq!(body.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn invalid_lengths_are_refused() {
+        for length in ["-1", "5x", "", "5, 6"] {
+            let (_, body) = read_body(
+                format!("PUT /a.txt HTTP/1.1\r\nContent-Length: {length}\r\n\r\nhello").as_bytes(),
+            );
+            assert_eq!(
+                body.unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData,
+                "{length}"
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_lengths_must_agree() {
+        let (_, body) = read_body(
+            b"PUT /a.txt HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\nhello",
+        );
+        assert_eq!(body.unwrap(), b"hello");
+        for repeated in ["Content-Length: 3", "content-length: 3", "CONTENT-LENGTH:3"] {
+            let (_, body) = read_body(
+                format!("PUT /a.txt HTTP/1.1\r\nContent-Length: 5\r\n{repeated}\r\n\r\nhello")
+                    .as_bytes(),
+            );
+            assert_eq!(
+                body.unwrap_err().kind(),
+                std::io::ErrorKind::InvalidData,
+                "{repeated}"
+            );
+        }
+    }
+
+    #[test]
+    fn length_and_transfer_encoding_are_refused_together() {
+        let (_, body) = read_body(
+            b"PUT /a.txt HTTP/1.1\r\nContent-Length: 5\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n",
+        );
+        assert_eq!(body.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn chunked_body_is_decoded() {
+        let (packet, body) = read_body(
+            b"PUT /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5;name=value\r\nhello\r\n6\r\n world\r\n0\r\nX-Trailer: done\r\n\r\n",
+        );
+        assert_eq!(body.unwrap(), b"hello world");
+        assert_eq!(
+            packet.trailers().get("X-Trailer").map(String::as_str),
+            Some("done")
+        );
+    }
+
+    #[test]
+    fn chunked_body_stops_at_the_last_chunk() {
+        let (mut packet, _client) = HttpRequest::from_bytes(
+            b"PUT /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\nGET / HTTP/1.1\r\n",
+        );
+        let mut body = Vec::new();
+        packet.body().unwrap().read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"abc");
+        let mut rest = Vec::new();
+        packet.stream.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn malformed_chunks_are_refused() {
+        let head = "PUT /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        for chunks in ["zz\r\nhello\r\n0\r\n\r\n", "5\r\nhelloX\r\n0\r\n\r\n"] {
+            let (_, body) = read_body(format!("{head}{chunks}").as_bytes());
+            assert_eq!(body.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+        }
+        let long_line = "0".repeat(ChunkedReader::MAX_LINE_LENGTH + 1);
+        let (_, body) = read_body(format!("{head}{long_line}\r\n").as_bytes());
+        assert_eq!(body.unwrap_err().kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn cut_short_chunks_are_refused() {
+        let (_, body) =
+            read_body(b"PUT /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhel");
+        assert_eq!(body.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+        // The trailers have to end too
+        let (_, body) = read_body(
+            b"PUT /a.txt HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\nX-Trailer: done\r\n",
+        );
+        assert_eq!(body.unwrap_err().kind(), std::io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn unsupported_transfer_encodings_are_refused() {
+        let (_, body) =
+            read_body(b"PUT /a.txt HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n");
+        assert_eq!(body.unwrap_err().kind(), std::io::ErrorKind::Unsupported);
+    }
+
+    #[test]
+    fn upload_keys_are_left_out_of_the_log() {
+        let (mut packet, _client) = HttpRequest::from_bytes(
+            b"GET /abc/a.txt?inline&key=s3cret HTTP/1.1\r\nX-Upload-Key: t0psecret\r\n\r\n",
+        );
+        packet.read_all();
+        packet
+            .respond(
+                "303 See Other",
+                &[("Location", "/abc/a.txt?key=s3cret")],
+                "",
+            )
+            .unwrap();
+        let logged = packet.to_string();
+        assert!(!logged.contains("s3cret"), "{logged}");
+        assert!(!logged.contains("t0psecret"), "{logged}");
+        assert!(logged.contains("?inline&key=(REDACTED) "), "{logged}");
+        assert!(logged.contains("X-Upload-Key: (REDACTED)"), "{logged}");
+    }
+
+    #[test]
+    fn passwords_are_left_out_of_the_log() {
+        let (mut packet, _client) = HttpRequest::from_bytes(
+            b"GET /abc/a.txt?password=hunter2&inline HTTP/1.1\r\nX-Upload-Password: hunter3\r\nauthorization: Basic Omh1bnRlcjQ=\r\n\r\n",
+        );
+        packet.read_all();
+        let logged = packet.to_string();
+        for secret in ["hunter2", "hunter3", "Omh1bnRlcjQ="] {
+            assert!(!logged.contains(secret), "{logged}");
+        }
+        assert!(logged.contains("?password=(REDACTED)&inline "), "{logged}");
+    }
+}
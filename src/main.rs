@@ -1,56 +1,112 @@
 use crate::{
-    http_methods::{get, put},
+    config::{Config, StorageBackend},
+    http_methods::{delete, get, options, patch, post, put},
     http_request::HttpRequest,
+    storage::{DedupStorage, LocalStorage, MemoryStorage, S3Storage, Storage},
+    thread_pool::ThreadPool,
 };
 use std::{
     cell::LazyCell,
-    ffi::OsStr,
     io::Write,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream},
-    os::unix::ffi::OsStrExt,
+    net::{SocketAddr, TcpListener, TcpStream},
     path::PathBuf,
-    sync::{Arc, LazyLock},
+    sync::LazyLock,
     thread::{self, sleep},
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 
+mod checksum;
+mod conditional;
+mod config;
 mod email;
+mod encryption;
+mod event_loop;
 mod http_methods;
 mod http_request;
+mod metadata;
+mod mime;
+mod multipart;
+mod password;
+mod range;
+mod resumable;
+mod storage;
+mod thread_pool;
 static ROOT_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
     PathBuf::from("./")
         .canonicalize()
         .expect("Missing \"./\" directory")
 });
-static SITE_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
-    PathBuf::from("./site")
-        .canonicalize()
-        .expect("Missing \"site\" directory")
-});
-static FILES_PATH: LazyLock<PathBuf> = LazyLock::new(|| {
-    PathBuf::from("./files").canonicalize().expect(&format!(
-        "Missing \"files\" directory, in {}",
-        std::env::current_dir().unwrap().display()
-    ))
-});
+static SITE_PATH: LazyLock<PathBuf> = LazyLock::new(|| Config::get().site_path.clone());
+static FILES_PATH: LazyLock<PathBuf> = LazyLock::new(|| Config::get().files_path.clone());
+/// The methods `handle_request` dispatches, as listed in the `Allow` header.
+const ALLOWED_METHODS: &str = "GET, HEAD, PUT, POST, PATCH, DELETE, OPTIONS";
 fn main() {
-    const MAX_THREADS: usize = 32;
-    const ADDRESS: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 80);
-    const FILE_LIFETIME: Duration = Duration::from_secs(60 * 60); // 1 Hours
-
-    if let Some(arg) = std::env::args().skip(1).next() {
-        if arg == "gc" {
-            log!("Garbage collector enabled");
-        } else {
-            log!("Garbage collector disabled, use \"gc\" argument to enable it.")
+    if std::env::args()
+        .skip(1)
+        .any(|arg| arg == "--help" || arg == "-h")
+    {
+        print!("{}", config::USAGE);
+        return;
+    }
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    // "gc --dry-run" lists what the garbage collector would delete, then exits
+    let dry_run =
+        args.first().is_some_and(|arg| arg == "gc") && args.contains(&"--dry-run".to_owned());
+    if dry_run {
+        args.retain(|arg| arg != "--dry-run");
+    }
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(err) => {
+            log!("Invalid configuration: {err}");
+            eprint!("\n{}", config::USAGE);
+            std::process::exit(2);
         }
+    };
+    config.install();
+    let config = Config::get();
+    let backend: Box<dyn Storage> = match config.storage.backend {
+        StorageBackend::Local => {
+            Box::new(LocalStorage::new(FILES_PATH.clone(), config.gc.lifetime()))
+        }
+        StorageBackend::Memory => Box::new(MemoryStorage::new()),
+        StorageBackend::S3 => Box::new(S3Storage::new(&config.storage.s3)),
+    };
+    if config.storage.dedup {
+        storage::install(Box::new(DedupStorage::new(backend)));
+    } else {
+        storage::install(backend);
+    }
+    if dry_run {
+        garbage_collect(storage::get(), true);
+        return;
+    }
+
+    if config.gc.enabled {
+        log!(
+            "Garbage collector enabled, deleting uploads older than {}s every {}s",
+            config.gc.lifetime_secs,
+            config.gc.interval_secs
+        );
+        garbage_collector_loop(config.gc.interval());
     } else {
-        log!("Garbage collector disabled, use \"gc\" argument to enable it.")
+        log!("Garbage collector disabled, use \"--gc\" argument to enable it.")
+    }
+    let evented = config.evented;
+    if evented {
+        log!("Using the evented server, slow clients are handled by epoll.");
+    } else {
+        log!("Using the threaded server, use \"--evented\" argument to handle slow clients with epoll.");
     }
-    garbage_collector_loop(FILE_LIFETIME);
     let server_thread = thread::Builder::new()
         .name("ServerThread".to_owned())
-        .spawn(|| host_server(SocketAddr::V4(ADDRESS), MAX_THREADS))
+        .spawn(move || {
+            if evented {
+                event_loop::host_server_evented(config.address, config.workers, config.queue_size)
+            } else {
+                host_server(config.address, config.workers, config.queue_size)
+            }
+        })
         .expect("Failed to spawn server");
     match server_thread.join() {
         Ok(Ok(_)) => log!("Server successfully closed."),
@@ -60,144 +116,227 @@ fn main() {
         Err(error) => log!("Server panicked! Panic message: {:?}", error),
     }
 }
-/// Creates a TcpListener on the provided address, accepting all incoming requests and sending the request to
+/// Creates a TcpListener on the provided address, accepting all incoming requests and queueing them for a pool of `workers` threads which run
 /// ```no_run
 /// handle_connection()
 /// ```
-/// to respond
+/// to respond. Once `queue_size` connections are waiting for a worker, new clients are sent a `503 Service Unavailable`.
 /// # Errors
-/// Returns an IO error if the TcpListener fails to bind to the requested address.
-fn host_server(address: SocketAddr, max_threads: usize) -> std::io::Result<()> {
+/// Returns an IO error if the TcpListener fails to bind to the requested address, or the worker threads fail to spawn.
+fn host_server(address: SocketAddr, workers: usize, queue_size: usize) -> std::io::Result<()> {
     let listener = TcpListener::bind(address)?;
-    let thread_count: Arc<()> = Arc::new(()); // Counts the number of threads spawned based on the weak count
+    let pool = ThreadPool::new("ClientHandler", workers, queue_size, move |client| {
+        handle_connection(client, address)
+    })?;
     log!("==================== Server running on {address} ====================");
     for client in listener.incoming().flatten() {
-        if Arc::strong_count(&thread_count) <= max_threads {
-            /* Ignores request if too many threads are spawned */
-            let passed_count = thread_count.clone();
-            let new_addr = address.clone();
-            if thread::Builder::new()
-                .name("ClientHandler".to_string())
-                .spawn(move || handle_connection(passed_count, client, new_addr))
-                .is_err()
-            {
-                /* Spawn thread to handle request */
-                log!("Failed to spawn thread");
-            }
+        log!(
+            "Accepted connection, {}/{} worker(s) busy, {} connection(s) queued.",
+            pool.busy(),
+            pool.workers(),
+            pool.queued()
+        );
+        if let Err(client) = pool.submit(client) {
+            log!("Worker pool saturated, turning client away.");
+            service_unavailable(client);
         }
-        sleep(Duration::from_millis(250))
     }
-
-    drop(thread_count);
     Ok(())
 }
-/// Takes in a threadcounter and TcpStream, reading the entire TCP packet before responding with the requested data. The `thread_counter` variable is dropped at the end of the function, such that the strong count represents the number of threads spawned.
-fn handle_connection(thread_counter: Arc<()>, client: TcpStream, address: SocketAddr) {
-    log!(
-        "{} Thread(s) active.",
-        Arc::strong_count(&thread_counter) - 1
+/// Tells a client the server is too busy to handle their request. This runs on the accepting thread, so it never waits on the client for long.
+fn service_unavailable(mut client: TcpStream) {
+    const RETRY_AFTER: u64 = 5; // Seconds
+    const BODY: &str = "Server busy, please try again shortly.\r\n";
+    let _ = client.set_write_timeout(Some(Duration::from_millis(100)));
+    let _ = client.write_all(
+        format!(
+            "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {RETRY_AFTER}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{BODY}",
+            BODY.len()
+        )
+        .as_bytes(),
     );
+    let _ = client.shutdown(std::net::Shutdown::Both);
+}
+/// Takes in a TcpStream, reading requests off the connection one after another and responding to each, until the client closes it, asks for it to be closed or leaves it idle.
+fn handle_connection(client: TcpStream, address: SocketAddr) {
     let client_ip = client.peer_addr();
     client
-        .set_read_timeout(Some(Duration::from_millis(5000)))
+        .set_read_timeout(Some(Config::get().timeout()))
         .expect("Should set read timeout");
     client
-        .set_write_timeout(Some(Duration::from_millis(5000)))
+        .set_write_timeout(Some(Config::get().timeout()))
         .expect("Should set write timeout");
     log!("Set read timeout");
-    let mut packet = HttpRequest::new(client);
-    if let Some(protocol) = packet.protocol() {
-        match protocol.as_str() {
-            "HTTP/1.1" | "undefined" => {
-                if let Some(method) = packet.method() {
-                    if let Ok(ip) = client_ip {
-                        log!("Client {ip} made a {method} request");
+    for request_count in 0.. {
+        if request_count > 0 {
+            let _ = client.set_read_timeout(Some(Config::get().keep_alive_timeout()));
+        }
+        let Ok(stream) = client.try_clone() else {
+            log!("Failed to clone client stream");
+            break;
+        };
+        let mut packet = HttpRequest::new(stream);
+        let protocol = packet.protocol(); // Waits for the request line
+        let _ = client.set_read_timeout(Some(Config::get().timeout()));
+        let Some(protocol) = protocol else {
+            if request_count == 0 {
+                log!("Client provided no protocol.");
+            }
+            break; // The connection was closed, timed out or sent garbage
+        };
+        handle_request(packet, protocol, client_ip.as_ref().ok(), address);
+    }
+    let _ = client.shutdown(std::net::Shutdown::Both);
+}
+/// Dispatches a single request to the handler for its method. Dropping the packet afterwards either readies the connection for the next request or closes it.
+fn handle_request(
+    mut packet: HttpRequest,
+    protocol: String,
+    client_ip: Option<&SocketAddr>,
+    address: SocketAddr,
+) {
+    match protocol.as_str() {
+        "HTTP/1.1" | "HTTP/1.0" | "undefined" => {
+            if let Some(method) = packet.method() {
+                if let Some(ip) = client_ip {
+                    log!("Client {ip} made a {method} request");
+                } else {
+                    log!("Client made a {method} request");
+                }
+                // Where the body ends has to be clear before anything is read, or the rest of the connection cant be trusted
+                if let Err(err) = packet.body().map(drop) {
+                    log!("Request rejected: {err}");
+                    let status = if err.kind() == std::io::ErrorKind::Unsupported {
+                        "501 Not Implemented"
                     } else {
-                        log!("Client made a {method} request");
-                    }
-                    match method.to_lowercase().trim() {
-                        "get" => get(packet, address),
-                        "put" => put(packet, address),
-                        _ => {
-                            log!("Invalid method, request ignored.");
-                            let _ = packet.respond_string("HTTP/1.1 405 Method Not Allowed\r\n\r\nUnknown request method. Allowed methods: \"GET\", \"PUT\", \"DELETE\".\r\n");
-                        }
+                        "400 Bad Request"
+                    };
+                    packet.close_connection();
+                    let _ = packet.respond(status, &[], &format!("{err}.\r\n"));
+                    return;
+                }
+                match method.to_lowercase().trim() {
+                    "get" | "head" => get(packet, address),
+                    "put" => put(packet, address),
+                    "post" => post(packet, address),
+                    "patch" => patch(packet, address),
+                    "delete" => delete(packet, address),
+                    "options" => options(packet, address),
+                    _ => {
+                        log!("Invalid method, request ignored.");
+                        let _ = packet.respond(
+                            "405 Method Not Allowed",
+                            &[("Allow", ALLOWED_METHODS)],
+                            &format!(
+                                "Unknown request method. Allowed methods: {ALLOWED_METHODS}.\r\n"
+                            ),
+                        );
                     }
-                } else {
-                    log!("No method provided");
-                    let _ = packet.respond_string("HTTP/1.1 400 Bad Request\r\n\r\nUnknown request method. Allowed methods: \"GET\", \"PUT\", \"DELETE\".\r\n");
                 }
-            }
-            proto => {
-                log!("Client used invalid protocol: \"{proto}\"");
-                let _ = packet.respond_string("Unknown protocol.");
+            } else {
+                log!("No method provided");
+                packet.close_connection();
+                let _ = packet.respond(
+                    "400 Bad Request",
+                    &[],
+                    &format!("Unknown request method. Allowed methods: {ALLOWED_METHODS}.\r\n"),
+                );
             }
         }
-    } else {
-        log!("Client provided no protocol.");
+        proto => {
+            log!("Client used invalid protocol: \"{proto}\"");
+            packet.close_connection();
+            let _ = packet.respond("505 HTTP Version Not Supported", &[], "Unknown protocol.");
+        }
     }
-
-    drop(thread_counter); // Decrements the counter
 }
 
-fn garbage_collect(lifetime: Duration) {
-    if let Ok(dir) = std::fs::read_dir(FILES_PATH.as_path()) {
-        for file in dir.flatten() {
-            if file.file_name() == OsStr::from_bytes(b"static") {
-                continue;
-            } else {
-                if let Ok(metadata) = file.metadata() {
-                    if let Ok(create_date) = metadata.created() {
-                        if let Ok(elapsed) = create_date.elapsed() {
-                            if elapsed > lifetime {
-                                log!(
-                                    "Attempting garbage collection of \"{}\"",
-                                    String::from_utf8_lossy(file.file_name().as_bytes())
-                                );
-                                match std::fs::remove_dir_all(file.path()) {
-                                    Ok(()) => {
-                                        log!(
-                                            "Successfully deleted \"{}\"",
-                                            String::from_utf8_lossy(file.file_name().as_bytes())
-                                        );
-                                    }
-                                    Err(err) => {
-                                        log!(
-                                            "Failed to delete \"{}\": {}",
-                                            String::from_utf8_lossy(file.file_name().as_bytes()),
-                                            err
-                                        );
-                                    }
-                                }
-                            }
-                        } else {
-                            log!(
-                                "Failed to get time since creation of \"{}\"",
-                                String::from_utf8_lossy(file.file_name().as_bytes())
-                            )
-                        }
-                    } else {
-                        log!(
-                            "Failed to get creation date of \"{}\"",
-                            String::from_utf8_lossy(file.file_name().as_bytes())
-                        )
-                    }
+/// Deletes every upload past the expiry in its metadata or out of downloads, then any stored files no upload refers to any more. With `dry_run`, nothing is deleted and what would be is printed instead.
+fn garbage_collect(storage: &dyn Storage, dry_run: bool) {
+    for (id, reason) in collectable(storage, SystemTime::now()) {
+        if dry_run {
+            println!("{id}\t{reason}");
+            continue;
+        }
+        log!("Attempting garbage collection of \"{id}\"");
+        match storage.delete(&id) {
+            Ok(()) => {
+                log!("Successfully deleted \"{id}\"");
+            }
+            Err(err) => {
+                log!("Failed to delete \"{id}\": {err}");
+            }
+        }
+    }
+    match storage.collect_garbage(dry_run) {
+        Ok(unused) => {
+            for id in unused {
+                if dry_run {
+                    println!("{id}\tunreferenced");
+                } else {
+                    log!("Deleted unreferenced \"{id}\"");
+                }
+            }
+        }
+        Err(err) => {
+            log!("Failed to delete unreferenced files: {err}");
+        }
+    }
+    match resumable::collect_abandoned(Config::get().gc.abandoned(), dry_run) {
+        Ok(abandoned) => {
+            for token in abandoned {
+                if dry_run {
+                    println!("{token}\tabandoned");
                 } else {
-                    log!(
-                        "Failed to get metadata of \"{}\"",
-                        String::from_utf8_lossy(file.file_name().as_bytes())
-                    );
+                    log!("Deleted abandoned resumable upload \"{token}\"");
                 }
             }
         }
+        Err(err) => {
+            log!("Failed to delete abandoned resumable uploads: {err}");
+        }
     }
 }
-fn garbage_collector_loop(lifetime: Duration) {
+/// The uploads in `storage` which the garbage collector deletes at `now`, each with why. Uploads being downloaded are left for a later run.
+fn collectable(storage: &dyn Storage, now: SystemTime) -> Vec<(String, String)> {
+    let ids = match storage.list() {
+        Ok(ids) => ids,
+        Err(err) => {
+            log!("Failed to list uploads: {err}");
+            return Vec::new();
+        }
+    };
+    ids.into_iter()
+        .filter_map(|id| {
+            let metadata = match storage.stat(&id) {
+                Ok(metadata) => metadata,
+                Err(err) => {
+                    log!("Failed to get metadata of \"{id}\": {err}");
+                    return None;
+                }
+            };
+            if storage::is_sending(&id) {
+                return None; // Left until the download is done, which deletes it if it was the last allowed
+            }
+            // Only left behind if the server stopped while sending the last download
+            let reason = if metadata.is_used_up() {
+                "used up".to_owned()
+            } else if let Ok(overdue) = now.duration_since(metadata.expires_at()) {
+                format!("expired {}s ago", overdue.as_secs())
+            } else {
+                return None; // Not expired yet
+            };
+            Some((id, reason))
+        })
+        .collect()
+}
+/// Runs the garbage collector every `interval` on its own thread.
+fn garbage_collector_loop(interval: Duration) {
     thread::Builder::new()
         .name("Garbage collector".to_owned())
         .spawn(move || loop {
-            garbage_collect(lifetime);
-            sleep(Duration::from_secs(60 * 60))
+            garbage_collect(storage::get(), false);
+            sleep(interval)
         })
         .expect("Failed to spawn garbage collector");
 }
@@ -215,3 +354,266 @@ macro_rules! log {
         eprintln!("[{} UTC] {}:{}:{}: {}", current_time.format("%Y-%m-%d %H:%M:%S"), file!(), line!(), column!(), format!($($arg)*));
     }};
 }
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, io::Read, sync::Once};
+
+    use super::*;
+
+    /// Installs the default config, with its folders in a temporary directory, and keeps uploads in memory.
+    pub fn setup() {
+        static SETUP: Once = Once::new();
+        SETUP.call_once(|| {
+            let dir = std::env::temp_dir().join(format!("poc_project-test-{}", std::process::id()));
+            std::fs::create_dir_all(dir.join("site")).unwrap();
+            std::fs::create_dir_all(dir.join("files")).unwrap();
+            Config {
+                site_path: dir.join("site"),
+                files_path: dir.join("files"),
+                ..Config::default()
+            }
+            .install();
+            storage::install(Box::new(MemoryStorage::new()));
+        });
+    }
+    /// A response read off a connection.
+    pub struct Response {
+        pub status: u16,
+        /// Keyed by lowercase name.
+        pub headers: HashMap<String, String>,
+        pub body: Vec<u8>,
+    }
+    impl Response {
+        /// Reads one response, whose body is either up to `Content-Length` long or runs to the end of the connection. Returns `None` if the connection is closed first.
+        pub fn read(reader: &mut impl Read) -> Option<Self> {
+            let mut head = Vec::new();
+            while !head.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8];
+                if reader.read(&mut byte).ok()? == 0 {
+                    return None;
+                }
+                head.push(byte[0]);
+            }
+            let head = String::from_utf8(head).unwrap();
+            let mut lines = head.lines();
+            let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+            let headers: HashMap<String, String> = lines
+                .filter_map(|line| line.split_once(':'))
+                .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_owned()))
+                .collect();
+            let mut body = Vec::new();
+            match headers.get("content-length") {
+                Some(length) => {
+                    // Replies to HEAD requests stop short, at the end of the connection
+                    reader
+                        .take(length.parse().unwrap())
+                        .read_to_end(&mut body)
+                        .unwrap();
+                }
+                None => {
+                    reader.read_to_end(&mut body).unwrap();
+                }
+            }
+            Some(Self {
+                status,
+                headers,
+                body,
+            })
+        }
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers.get(&name.to_lowercase()).map(String::as_str)
+        }
+    }
+    /// Opens a connection to a worker running [`handle_connection`].
+    fn connect() -> TcpStream {
+        setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = TcpStream::connect(address).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        thread::spawn(move || handle_connection(server, address));
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        client
+    }
+    /// Whether the server has closed the connection, which it may have reset if the client was still sending.
+    pub fn closed(client: &mut TcpStream) -> bool {
+        match client.read(&mut [0u8]) {
+            Ok(bytes_read) => bytes_read == 0,
+            Err(err) => err.kind() == std::io::ErrorKind::ConnectionReset,
+        }
+    }
+
+    /// Handles a single request, returning everything sent back.
+    fn respond(raw: &[u8]) -> String {
+        let (packet, mut client) = HttpRequest::from_bytes(raw);
+        let address = "127.0.0.1:8080".parse().unwrap();
+        handle_request(packet, "HTTP/1.1".to_owned(), None, address);
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn ambiguous_bodies_are_refused() {
+        for headers in [
+            "Content-Length: 5\r\nContent-Length: 6",
+            "Content-Length: 5\r\nTransfer-Encoding: chunked",
+        ] {
+            let response = respond(
+                format!("PUT /a.txt HTTP/1.1\r\nHost: localhost\r\n{headers}\r\n\r\nhello")
+                    .as_bytes(),
+            );
+            assert!(response.starts_with("HTTP/1.1 400 "), "{response}");
+            assert!(response.contains("\r\nConnection: close\r\n"), "{response}");
+        }
+        let response = respond(b"PUT /a.txt HTTP/1.1\r\nTransfer-Encoding: gzip\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 501 "), "{response}");
+    }
+
+    #[test]
+    fn requests_share_a_connection() {
+        let mut client = connect();
+        client
+            .write_all(b"OPTIONS / HTTP/1.1\r\nHost: localhost\r\n\r\nOPTIONS / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        for _ in 0..2 {
+            let response = Response::read(&mut client).unwrap();
+            assert_eq!(response.status, 204);
+            assert_eq!(response.header("Connection"), None);
+            assert!(response.body.is_empty());
+        }
+        client
+            .write_all(b"OPTIONS / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+            .unwrap();
+        assert_eq!(Response::read(&mut client).unwrap().status, 204);
+    }
+
+    #[test]
+    fn connection_close_is_honoured() {
+        let mut client = connect();
+        client
+            .write_all(b"OPTIONS / HTTP/1.1\r\nConnection: close\r\n\r\nOPTIONS / HTTP/1.1\r\n\r\n")
+            .unwrap();
+        let response = Response::read(&mut client).unwrap();
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert!(closed(&mut client));
+    }
+
+    #[test]
+    fn http_1_0_closes_unless_asked() {
+        let mut client = connect();
+        client.write_all(b"OPTIONS / HTTP/1.0\r\n\r\n").unwrap();
+        let response = Response::read(&mut client).unwrap();
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert!(closed(&mut client));
+        let mut client = connect();
+        client
+            .write_all(b"OPTIONS / HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+            .unwrap();
+        let response = Response::read(&mut client).unwrap();
+        assert_eq!(response.header("Connection"), Some("keep-alive"));
+        client.write_all(b"OPTIONS / HTTP/1.0\r\n\r\n").unwrap();
+        assert_eq!(Response::read(&mut client).unwrap().status, 204);
+        assert!(closed(&mut client));
+    }
+
+    #[test]
+    fn unread_bodies_are_skipped() {
+        let mut client = connect();
+        client
+            .write_all(b"OPTIONS / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloOPTIONS / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nabc\r\n0\r\n\r\nOPTIONS / HTTP/1.1\r\n\r\n")
+            .unwrap();
+        for _ in 0..3 {
+            assert_eq!(Response::read(&mut client).unwrap().status, 204);
+        }
+        // Past the most that is skipped, it is cheaper to close the connection
+        let mut client = connect();
+        let length = 1024 * 1024;
+        client
+            .write_all(format!("OPTIONS / HTTP/1.1\r\nContent-Length: {length}\r\n\r\n").as_bytes())
+            .unwrap();
+        let writer = client.try_clone().unwrap();
+        thread::spawn(move || std::io::copy(&mut std::io::repeat(b'a').take(length), &mut &writer));
+        assert_eq!(Response::read(&mut client).unwrap().status, 204);
+        assert!(closed(&mut client));
+    }
+
+    #[test]
+    fn busy_servers_turn_clients_away() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        service_unavailable(server);
+        let response = Response::read(&mut client).unwrap();
+        assert_eq!(response.status, 503);
+        assert_eq!(response.header("Retry-After"), Some("5"));
+        assert_eq!(response.header("Connection"), Some("close"));
+        assert!(closed(&mut client));
+    }
+
+    #[test]
+    fn head_and_options_are_routed() {
+        setup();
+        let head = respond(b"HEAD /missing/file.txt HTTP/1.1\r\n\r\n");
+        assert!(head.starts_with("HTTP/1.1 410 "), "{head}");
+        assert!(head.ends_with("\r\n\r\n"), "{head}");
+        let options = respond(b"OPTIONS * HTTP/1.1\r\n\r\n");
+        assert!(options.starts_with("HTTP/1.1 204 "), "{options}");
+        let unknown = respond(b"BREW / HTTP/1.1\r\n\r\n");
+        assert!(unknown.starts_with("HTTP/1.1 405 "), "{unknown}");
+        assert!(unknown.contains(&format!("Allow: {ALLOWED_METHODS}\r\n")));
+    }
+
+    #[test]
+    fn dry_runs_only_list_what_would_go() {
+        setup();
+        let storage = MemoryStorage::new();
+        let lifetime = Duration::from_secs(60);
+        let live = metadata::Metadata::new("live.txt", lifetime);
+        storage.create("live", &live).unwrap();
+        let mut expired = live.clone();
+        expired.expires -= 120;
+        storage.create("expired", &expired).unwrap();
+        let mut used_up = live.clone();
+        used_up.max_downloads = Some(1);
+        used_up.downloads = 1;
+        storage.create("used-up", &used_up).unwrap();
+        let now = expired.expires_at() + Duration::from_secs(30);
+        let mut found = collectable(&storage, now);
+        found.sort();
+        assert_eq!(
+            found,
+            [
+                ("expired".to_owned(), "expired 30s ago".to_owned()),
+                ("used-up".to_owned(), "used up".to_owned()),
+            ]
+        );
+        garbage_collect(&storage, true);
+        for id in ["live", "expired", "used-up"] {
+            assert!(storage.stat(id).is_ok(), "{id}");
+        }
+        garbage_collect(&storage, false);
+        assert!(storage.stat("live").is_ok());
+        for id in ["expired", "used-up"] {
+            assert_eq!(
+                storage.stat(id).unwrap_err().kind(),
+                std::io::ErrorKind::NotFound
+            );
+        }
+    }
+
+    #[test]
+    fn uploads_being_sent_are_left_alone() {
+        let storage = MemoryStorage::new();
+        let mut used_up = metadata::Metadata::new("sending.txt", Duration::from_secs(60));
+        used_up.max_downloads = Some(1);
+        used_up.downloads = 1;
+        storage.create("used-up-sending", &used_up).unwrap();
+        let sending = storage::Sending::new("used-up-sending");
+        assert!(collectable(&storage, SystemTime::now()).is_empty());
+        drop(sending);
+        assert_eq!(collectable(&storage, SystemTime::now()).len(), 1);
+    }
+}
@@ -0,0 +1,122 @@
+use std::{
+    io::{self, Write},
+    net::IpAddr,
+    os::unix::ffi::OsStrExt,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// The file in each upload's folder holding its [`Metadata`], when stored on disk.
+const METADATA_FILE: &str = ".meta";
+/// Where metadata is written before being renamed over [`METADATA_FILE`], so a crash cant leave it half written.
+const METADATA_TEMP_FILE: &str = ".meta.tmp";
+
+/// Everything known about an upload, kept in a file alongside it so it survives restarts.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Metadata {
+    /// The uploaded file's name, as given by the uploader.
+    pub file_name: String,
+    /// The file's size in bytes, 0 until the upload has finished.
+    pub size: u64,
+    /// The `Content-Type` the uploader sent.
+    pub content_type: Option<String>,
+    pub uploader_ip: Option<IpAddr>,
+    /// When the upload started, in seconds since the Unix epoch.
+    pub created: u64,
+    /// When the upload expires, in seconds since the Unix epoch.
+    pub expires: u64,
+    /// The token needed to delete the upload early.
+    pub delete_token: Option<String>,
+    /// How many times the upload can be downloaded before it is deleted.
+    pub max_downloads: Option<u64>,
+    pub downloads: u64,
+    /// The hex encoded SHA-256 digest of the file. Encrypted uploads only have one when deduplicated storage keeps it, and then it is of the encrypted file.
+    pub sha256: Option<String>,
+    /// The id of the blob the file is kept in, when uploads are deduplicated.
+    pub blob: Option<String>,
+    /// Set when the file is encrypted, so a wrong key can be refused before decrypting anything. See [`crate::encryption::UploadKey::check`].
+    pub key_check: Option<String>,
+    /// The salted hash of the password needed to download the upload, see [`crate::password::hash`].
+    pub password_hash: Option<String>,
+    /// The size a resumable upload will be once every chunk has arrived, only set while it is still arriving.
+    pub upload_length: Option<u64>,
+    /// Set when the metadata was lost and rebuilt from the upload's folder. Whatever protected the upload, such as its password, key or download limit, went with it, so it is never served again, only kept until it expires.
+    #[serde(default)]
+    pub rebuilt: bool,
+}
+impl Metadata {
+    /// Metadata for an upload starting now, which will be kept for `lifetime`.
+    pub fn new(file_name: &str, lifetime: Duration) -> Self {
+        let created = unix_secs(SystemTime::now());
+        Self {
+            file_name: file_name.to_owned(),
+            created,
+            expires: created.saturating_add(lifetime.as_secs()),
+            ..Self::default()
+        }
+    }
+    /// Reads the metadata of the upload in `dir_location`.
+    /// # Errors
+    /// Returns a `NotFound` error if the upload has no metadata, or an `InvalidData` error if it cant be parsed.
+    pub fn load(dir_location: &Path) -> io::Result<Self> {
+        Self::from_toml(&std::fs::read_to_string(dir_location.join(METADATA_FILE))?)
+    }
+    /// Parses metadata stored as TOML.
+    /// # Errors
+    /// Returns an `InvalidData` error if it cant be parsed.
+    pub fn from_toml(text: &str) -> io::Result<Self> {
+        toml::from_str(text).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+    pub fn to_toml(&self) -> io::Result<String> {
+        toml::to_string(self).map_err(io::Error::other)
+    }
+    /// Works out what it can from an upload's folder, for uploads whose metadata is missing or corrupt, so the garbage collector can still expire them. Anything only the uploader knew, such as the delete token or password, is lost, so the result is marked [`Metadata::rebuilt`].
+    pub fn rebuild(dir_location: &Path, lifetime: Duration) -> io::Result<Self> {
+        let dir_metadata = std::fs::metadata(dir_location)?;
+        let created = dir_metadata
+            .created()
+            .or_else(|_| dir_metadata.modified())?;
+        let file = std::fs::read_dir(dir_location)?.flatten().find(|entry| {
+            !entry.file_name().as_bytes().starts_with(b".")
+                && entry.file_type().is_ok_and(|file_type| file_type.is_file())
+        });
+        let mut metadata = Self {
+            created: unix_secs(created),
+            expires: unix_secs(created).saturating_add(lifetime.as_secs()),
+            rebuilt: true,
+            ..Self::default()
+        };
+        if let Some(file) = file {
+            metadata.file_name = String::from_utf8_lossy(file.file_name().as_bytes()).into_owned();
+            metadata.size = file.metadata().map(|file| file.len()).unwrap_or(0);
+        }
+        Ok(metadata)
+    }
+    /// Writes the metadata alongside the upload in `dir_location`.
+    pub fn write(&self, dir_location: &Path) -> io::Result<()> {
+        let text = self.to_toml()?;
+        let temp_location = dir_location.join(METADATA_TEMP_FILE);
+        let mut file = std::fs::File::create(&temp_location)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(temp_location, dir_location.join(METADATA_FILE))
+    }
+    pub fn expires_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.expires)
+    }
+    pub fn is_expired(&self) -> bool {
+        self.expires_at() <= SystemTime::now()
+    }
+    /// Whether every download allowed has been taken, so the upload is only kept until the last one has been sent.
+    pub fn is_used_up(&self) -> bool {
+        self.max_downloads
+            .is_some_and(|max_downloads| self.downloads >= max_downloads)
+    }
+}
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
@@ -0,0 +1,220 @@
+/// How much of a file is looked at when guessing its type from its contents.
+pub const SNIFF_LEN: usize = 512;
+
+/// Media types by file extension, compared ignoring case. Text types say they are UTF-8, as that is what the site is written in.
+const EXTENSIONS: &[(&str, &str)] = &[
+    ("html", "text/html; charset=utf-8"),
+    ("htm", "text/html; charset=utf-8"),
+    ("css", "text/css; charset=utf-8"),
+    ("js", "text/javascript; charset=utf-8"),
+    ("mjs", "text/javascript; charset=utf-8"),
+    ("json", "application/json"),
+    ("xml", "application/xml"),
+    ("txt", "text/plain; charset=utf-8"),
+    ("md", "text/markdown; charset=utf-8"),
+    ("csv", "text/csv; charset=utf-8"),
+    ("png", "image/png"),
+    ("jpg", "image/jpeg"),
+    ("jpeg", "image/jpeg"),
+    ("gif", "image/gif"),
+    ("webp", "image/webp"),
+    ("avif", "image/avif"),
+    ("svg", "image/svg+xml"),
+    ("ico", "image/vnd.microsoft.icon"),
+    ("bmp", "image/bmp"),
+    ("pdf", "application/pdf"),
+    ("zip", "application/zip"),
+    ("gz", "application/gzip"),
+    ("tar", "application/x-tar"),
+    ("7z", "application/x-7z-compressed"),
+    ("wasm", "application/wasm"),
+    ("mp3", "audio/mpeg"),
+    ("ogg", "audio/ogg"),
+    ("wav", "audio/wav"),
+    ("flac", "audio/flac"),
+    ("mp4", "video/mp4"),
+    ("webm", "video/webm"),
+    ("mkv", "video/x-matroska"),
+    ("mov", "video/quicktime"),
+    ("woff", "font/woff"),
+    ("woff2", "font/woff2"),
+    ("ttf", "font/ttf"),
+    ("otf", "font/otf"),
+];
+/// Signatures at the start of a file, and the type they mean. `None` matches any byte.
+const SIGNATURES: &[(&[Option<u8>], &str)] = &[
+    (&bytes(b"\x89PNG\r\n\x1a\n"), "image/png"),
+    (&bytes(b"\xff\xd8\xff"), "image/jpeg"),
+    (&bytes(b"GIF87a"), "image/gif"),
+    (&bytes(b"GIF89a"), "image/gif"),
+    (&riff(b"WEBP"), "image/webp"),
+    (&riff(b"WAVE"), "audio/wav"),
+    (&bytes(b"%PDF-"), "application/pdf"),
+    (&bytes(b"PK\x03\x04"), "application/zip"),
+    (&bytes(b"\x1f\x8b"), "application/gzip"),
+    (&bytes(b"7z\xbc\xaf\x27\x1c"), "application/x-7z-compressed"),
+    (&bytes(b"\0asm"), "application/wasm"),
+    (&bytes(b"ID3"), "audio/mpeg"),
+    (&bytes(b"OggS"), "audio/ogg"),
+    (&bytes(b"fLaC"), "audio/flac"),
+    (&bytes(b"\x1a\x45\xdf\xa3"), "video/webm"),
+    (&iso_media(), "video/mp4"),
+    (&bytes(b"wOFF"), "font/woff"),
+    (&bytes(b"wOF2"), "font/woff2"),
+];
+/// A signature which is exactly these bytes.
+const fn bytes<const N: usize>(signature: &[u8; N]) -> [Option<u8>; N] {
+    let mut pattern = [None; N];
+    let mut i = 0;
+    while i < N {
+        pattern[i] = Some(signature[i]);
+        i += 1;
+    }
+    pattern
+}
+/// An ISO base media file (MP4, MOV, ...), whose first box is `ftyp` after a 4 byte size.
+const fn iso_media() -> [Option<u8>; 8] {
+    let mut pattern = [None; 8];
+    let mut i = 0;
+    while i < 4 {
+        pattern[i + 4] = Some(b"ftyp"[i]);
+        i += 1;
+    }
+    pattern
+}
+/// A RIFF container of the given form, e.g. `RIFF????WEBP`.
+const fn riff(form: &[u8; 4]) -> [Option<u8>; 12] {
+    let mut pattern = [None; 12];
+    let mut i = 0;
+    while i < 4 {
+        pattern[i] = Some(b"RIFF"[i]);
+        pattern[i + 8] = Some(form[i]);
+        i += 1;
+    }
+    pattern
+}
+
+/// The media type of a file with this name, from its extension.
+pub fn from_name(name: &str) -> Option<&'static str> {
+    let (_, extension) = name.rsplit_once('.')?;
+    EXTENSIONS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(extension))
+        .map(|(_, media_type)| *media_type)
+}
+/// Guesses a file's media type from its first [`SNIFF_LEN`] bytes, for files whose name doesnt give it away. Markup is never guessed, so an upload cant be turned into a page by its contents alone.
+pub fn sniff(start: &[u8]) -> &'static str {
+    let signature = SIGNATURES.iter().find(|(pattern, _)| {
+        start.len() >= pattern.len()
+            && pattern
+                .iter()
+                .zip(start)
+                .all(|(expected, byte)| expected.is_none_or(|expected| expected == *byte))
+    });
+    if let Some((_, media_type)) = signature {
+        media_type
+    } else if looks_like_text(start) {
+        "text/plain; charset=utf-8"
+    } else {
+        "application/octet-stream"
+    }
+}
+/// Whether the bytes are UTF-8 without control characters other than whitespace. A character cut off at the end still counts.
+fn looks_like_text(start: &[u8]) -> bool {
+    let text = match std::str::from_utf8(start) {
+        Ok(text) => text,
+        Err(err) if err.error_len().is_none() => {
+            std::str::from_utf8(&start[..err.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+    !text
+        .chars()
+        .any(|char| char.is_control() && !matches!(char, '\n' | '\r' | '\t' | '\x0c'))
+}
+/// Whether a `Content-Type` an uploader declared is safe to send back and says something, e.g. `image/png` but not `application/octet-stream`.
+pub fn useful_declared(media_type: &str) -> bool {
+    let essence = media_type.split(';').next().unwrap_or_default().trim();
+    essence.contains('/')
+        && !essence.eq_ignore_ascii_case("application/octet-stream")
+        && media_type
+            .bytes()
+            .all(|byte| byte.is_ascii_graphic() || byte == b' ')
+}
+/// Whether a file of `media_type` is text underneath, e.g. HTML, SVG or JSON, so it can be shown safely as `text/plain`.
+pub fn is_textual(media_type: &str) -> bool {
+    let essence = media_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(
+            essence.as_str(),
+            "application/json" | "application/xml" | "application/javascript"
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signatures() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR"), "image/png");
+        assert_eq!(sniff(b"%PDF-1.7\n"), "application/pdf");
+        assert_eq!(sniff(b"RIFF\x10\0\0\0WEBPVP8 "), "image/webp");
+        assert_eq!(sniff(b"\0\0\0\x18ftypmp42"), "video/mp4");
+        // Too short to hold the whole signature
+        assert_eq!(sniff(b"\x89PNG"), "application/octet-stream");
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(sniff(b"hello\r\n\tworld"), "text/plain; charset=utf-8");
+        assert_eq!(sniff(b""), "text/plain; charset=utf-8");
+        // A character cut off by the end of what was read is still text
+        assert_eq!(
+            sniff("caf\u{e9}".as_bytes()[..4].as_ref()),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(sniff(b"\xff\xfe"), "application/octet-stream");
+        assert_eq!(sniff(b"a\0b"), "application/octet-stream");
+    }
+
+    #[test]
+    fn markup_is_never_guessed() {
+        assert_eq!(
+            sniff(b"<!DOCTYPE html><script>"),
+            "text/plain; charset=utf-8"
+        );
+        assert_eq!(
+            sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\">"),
+            "text/plain; charset=utf-8"
+        );
+    }
+
+    #[test]
+    fn names() {
+        assert_eq!(from_name("photo.JPG"), Some("image/jpeg"));
+        assert_eq!(from_name("archive.tar.gz"), Some("application/gzip"));
+        assert_eq!(from_name("README"), None);
+    }
+
+    #[test]
+    fn declared_types() {
+        assert!(useful_declared("image/png"));
+        assert!(useful_declared("text/plain; charset=utf-8"));
+        assert!(!useful_declared("application/octet-stream"));
+        assert!(!useful_declared("Application/Octet-Stream; x=y"));
+        assert!(!useful_declared("png"));
+        assert!(!useful_declared("text/html\r\nX-Injected: yes"));
+        assert!(is_textual("text/html; charset=utf-8"));
+        assert!(is_textual("image/svg+xml"));
+        assert!(is_textual("APPLICATION/JSON"));
+        assert!(!is_textual("image/png"));
+    }
+}
@@ -0,0 +1,294 @@
+use std::io::{self, Read};
+
+/// The longest part header line accepted, to stop a client sending an endless line.
+const MAX_LINE_LENGTH: usize = 4096;
+/// The most header lines accepted for a single part.
+const MAX_HEADERS: usize = 32;
+
+/// The headers of one part of a `multipart/form-data` body.
+#[derive(Debug)]
+pub struct Part {
+    /// The form field the part belongs to.
+    pub name: Option<String>,
+    /// The name of the uploaded file, only present for file fields.
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+enum State {
+    /// Before the first delimiter, the preamble is skipped.
+    Start,
+    /// Inside a part's body.
+    Body,
+    /// Just past a delimiter, the part headers or the closing `--` come next.
+    Delimiter,
+    /// Past the closing delimiter.
+    Done,
+}
+
+/// Streams the parts of a `multipart/form-data` body without buffering them, call [`Multipart::next_part`] to move to each part and read its body through [`Read`].
+pub struct Multipart<R: Read> {
+    reader: R,
+    /// `"\r\n--"` followed by the boundary, which ends every part.
+    delimiter: Vec<u8>,
+    /// Bytes read from `reader` but not yet consumed.
+    buf: Vec<u8>,
+    eof: bool,
+    state: State,
+}
+impl<R: Read> Multipart<R> {
+    pub fn new(reader: R, boundary: &str) -> Self {
+        Self {
+            reader,
+            delimiter: format!("\r\n--{boundary}").into_bytes(),
+            // The first delimiter doesnt have to follow a CRLF, so pretend it does
+            buf: b"\r\n".to_vec(),
+            eof: false,
+            state: State::Start,
+        }
+    }
+    /// Finds the boundary in a `multipart/form-data` content type, e.g. `multipart/form-data; boundary=abc`.
+    pub fn boundary(content_type: &str) -> Option<String> {
+        let (mime, params) = content_type.split_once(';')?;
+        if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+            return None;
+        }
+        param(params, "boundary").filter(|boundary| !boundary.is_empty() && boundary.len() <= 70)
+    }
+    /// Skips the rest of the current part and reads the headers of the next one, returning `None` once every part has been read.
+    /// # Errors
+    /// Returns an `InvalidData` error if the body is malformed, or an `UnexpectedEof` error if it ends before the closing delimiter.
+    pub fn next_part(&mut self) -> io::Result<Option<Part>> {
+        if let State::Start | State::Body = self.state {
+            io::copy(self, &mut io::sink())?;
+        }
+        if let State::Done = self.state {
+            return Ok(None);
+        }
+        // Right after a delimiter, "--" marks the end, otherwise the rest of the line is padding
+        let line = self.read_line()?;
+        if line.starts_with("--") {
+            self.state = State::Done;
+            return Ok(None);
+        }
+        if !line.trim().is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Unexpected data after boundary",
+            ));
+        }
+        let mut part = Part {
+            name: None,
+            filename: None,
+            content_type: None,
+        };
+        for _ in 0..=MAX_HEADERS {
+            let line = self.read_line()?;
+            if line.is_empty() {
+                self.state = State::Body;
+                return Ok(Some(part));
+            }
+            let Some((header, value)) = line.split_once(':') else {
+                continue;
+            };
+            if header.trim().eq_ignore_ascii_case("Content-Disposition") {
+                part.name = param(value, "name");
+                part.filename = param(value, "filename");
+            } else if header.trim().eq_ignore_ascii_case("Content-Type") {
+                part.content_type = Some(value.trim().to_owned());
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Too many part headers",
+        ))
+    }
+    /// Reads more of the body into the buffer, returning false once there is nothing left.
+    fn fill(&mut self) -> io::Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        let mut chunk = [0u8; 8192];
+        let bytes_read = self.reader.read(&mut chunk)?;
+        if bytes_read == 0 {
+            self.eof = true;
+        }
+        self.buf.extend_from_slice(&chunk[0..bytes_read]);
+        Ok(bytes_read > 0)
+    }
+    fn read_line(&mut self) -> io::Result<String> {
+        loop {
+            if let Some(end) = self.buf.windows(2).position(|window| window == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[0..end]).into_owned();
+                self.buf.drain(0..end + 2);
+                return Ok(line);
+            }
+            if self.buf.len() > MAX_LINE_LENGTH {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Part header line too long",
+                ));
+            }
+            if !self.fill()? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+impl<R: Read> Read for Multipart<R> {
+    /// Reads the current part's body, stopping at the delimiter which ends it.
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if !matches!(self.state, State::Start | State::Body) || out.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            let delimiter_start = self
+                .buf
+                .windows(self.delimiter.len())
+                .position(|window| window == self.delimiter.as_slice());
+            // Anything which cant be the start of a delimiter is safe to hand out
+            let available = delimiter_start
+                .unwrap_or_else(|| self.buf.len().saturating_sub(self.delimiter.len() - 1));
+            if let State::Start = self.state {
+                self.buf.drain(0..available); // The preamble is thrown away
+            } else if available > 0 {
+                let count = available.min(out.len());
+                out[0..count].copy_from_slice(&self.buf[0..count]);
+                self.buf.drain(0..count);
+                return Ok(count);
+            }
+            if delimiter_start.is_some() {
+                self.buf.drain(0..self.delimiter.len());
+                self.state = State::Delimiter;
+                return Ok(0);
+            }
+            if !self.fill()? {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+/// Finds a parameter in a header value such as `form-data; name="file"; filename="a.txt"`, removing any quotes.
+fn param(value: &str, name: &str) -> Option<String> {
+    value.split(';').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case(name) {
+            return None;
+        }
+        let value = value.trim();
+        Some(
+            value
+                .strip_prefix('"')
+                .and_then(|value| value.strip_suffix('"'))
+                .unwrap_or(value)
+                .replace("\\\"", "\""),
+        )
+    })
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hands out a byte at a time, so delimiters arrive split across reads.
+    struct Trickle<'a>(&'a [u8]);
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            if buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = *first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    const BODY: &[u8] = b"preamble\r\n--abc\r\nContent-Disposition: form-data; name=\"note\"\r\n\r\nhi\r\n--abc\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a \\\"b\\\".txt\"\r\nContent-Type: text/plain\r\n\r\nline one\r\n--ab not yet\r\n--abc--\r\nepilogue";
+
+    fn parts(reader: impl Read) -> io::Result<Vec<(Part, Vec<u8>)>> {
+        let mut multipart = Multipart::new(reader, "abc");
+        let mut parts = Vec::new();
+        while let Some(part) = multipart.next_part()? {
+            let mut body = Vec::new();
+            multipart.read_to_end(&mut body)?;
+            parts.push((part, body));
+        }
+        Ok(parts)
+    }
+
+    #[test]
+    fn parts_are_split() {
+        for parts in [parts(BODY).unwrap(), parts(Trickle(BODY)).unwrap()] {
+            assert_eq!(parts.len(), 2);
+            let (note, body) = &parts[0];
+            assert_eq!(note.name.as_deref(), Some("note"));
+            assert_eq!(note.filename, None);
+            assert_eq!(body, b"hi");
+            let (file, body) = &parts[1];
+            assert_eq!(file.name.as_deref(), Some("file"));
+            assert_eq!(file.filename.as_deref(), Some("a \"b\".txt"));
+            assert_eq!(file.content_type.as_deref(), Some("text/plain"));
+            assert_eq!(body, b"line one\r\n--ab not yet");
+        }
+    }
+
+    #[test]
+    fn unread_parts_are_skipped() {
+        let mut multipart = Multipart::new(BODY, "abc");
+        assert!(multipart.next_part().unwrap().is_some());
+        let file = multipart.next_part().unwrap().unwrap();
+        assert_eq!(file.name.as_deref(), Some("file"));
+        assert!(multipart.next_part().unwrap().is_none());
+    }
+
+    #[test]
+    fn malformed_bodies_are_refused() {
+        let cut_short = &BODY[..BODY.len() - 20];
+        assert_eq!(
+            parts(cut_short).unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+        let junk = b"--abcjunk\r\n\r\nhi\r\n--abc--\r\n";
+        assert_eq!(
+            parts(junk.as_slice()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let headers = "X: y\r\n".repeat(MAX_HEADERS + 1);
+        let many_headers = format!("--abc\r\n{headers}\r\nhi\r\n--abc--\r\n");
+        assert_eq!(
+            parts(many_headers.as_bytes()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn boundaries() {
+        assert_eq!(
+            Multipart::<io::Empty>::boundary("multipart/form-data; boundary=abc").as_deref(),
+            Some("abc")
+        );
+        assert_eq!(
+            Multipart::<io::Empty>::boundary(
+                "Multipart/Form-Data; charset=utf-8; boundary=\"a b\""
+            )
+            .as_deref(),
+            Some("a b")
+        );
+        assert_eq!(
+            Multipart::<io::Empty>::boundary("multipart/form-data"),
+            None
+        );
+        assert_eq!(
+            Multipart::<io::Empty>::boundary("text/plain; boundary=abc"),
+            None
+        );
+        assert_eq!(
+            Multipart::<io::Empty>::boundary("multipart/form-data; boundary="),
+            None
+        );
+        let long = format!("multipart/form-data; boundary={}", "a".repeat(71));
+        assert_eq!(Multipart::<io::Empty>::boundary(&long), None);
+    }
+}
@@ -0,0 +1,62 @@
+use pbkdf2::pbkdf2_hmac;
+use sha2::Sha256;
+
+/// How many times PBKDF2 hashes a password, making each guess slow for anyone holding the metadata.
+const ROUNDS: u32 = 100_000;
+const SCHEME: &str = "pbkdf2-sha256";
+
+/// Hashes an upload's password with `salt`, as `pbkdf2-sha256$<rounds>$<salt>$<hex hash>`, which is all [`rehash`] needs to check a password later.
+pub fn hash(password: &str, salt: &str) -> String {
+    hash_with(password, salt, ROUNDS)
+}
+fn hash_with(password: &str, salt: &str, rounds: u32) -> String {
+    let mut hash = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut hash);
+    let hash: String = hash.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("{SCHEME}${rounds}${salt}${hash}")
+}
+/// Hashes `password` the same way as a stored hash, so the two are equal if the password is right. Returns `None` if the stored hash isnt one [`hash`] made.
+pub fn rehash(password: &str, stored: &str) -> Option<String> {
+    let mut fields = stored.split('$');
+    if fields.next()? != SCHEME {
+        return None;
+    }
+    let rounds = fields.next()?.parse().ok()?;
+    let salt = fields.next()?;
+    Some(hash_with(password, salt, rounds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rehash_matches_only_the_right_password() {
+        let stored = hash_with("hunter2", "salt", 1000);
+        assert_eq!(rehash("hunter2", &stored).as_deref(), Some(stored.as_str()));
+        assert_ne!(rehash("hunter3", &stored).as_deref(), Some(stored.as_str()));
+        assert!(stored.starts_with("pbkdf2-sha256$1000$salt$"));
+    }
+
+    #[test]
+    fn salts_change_the_hash() {
+        assert_ne!(
+            hash_with("hunter2", "a", 1000),
+            hash_with("hunter2", "b", 1000)
+        );
+    }
+
+    #[test]
+    fn the_stored_rounds_are_used() {
+        let stored = hash("hunter2", "salt");
+        assert_eq!(rehash("hunter2", &stored).as_deref(), Some(stored.as_str()));
+        assert_ne!(hash_with("hunter2", "salt", 1000), stored);
+    }
+
+    #[test]
+    fn unknown_hashes_are_refused() {
+        assert_eq!(rehash("hunter2", "md5$1000$salt$abc"), None);
+        assert_eq!(rehash("hunter2", "pbkdf2-sha256$many$salt$abc"), None);
+        assert_eq!(rehash("hunter2", ""), None);
+    }
+}
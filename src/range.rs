@@ -0,0 +1,211 @@
+/// The most ranges served from one request, after overlapping ones are merged. Past this the whole file is sent instead, as lots of tiny ranges cost far more to send than the file.
+const MAX_RANGES: usize = 16;
+
+/// The parts of a file a `Range` header asks for.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Ranges {
+    /// No usable `Range` header, so the whole file is sent.
+    Full,
+    /// Inclusive byte ranges in order through the file, none of which overlap or touch.
+    Partial(Vec<(u64, u64)>),
+    /// Every range starts past the end of the file.
+    Unsatisfiable,
+}
+/// Reads a `Range` header such as `bytes=0-99,200-,-50` for a file of `len` bytes. Headers which cant be parsed are ignored, as RFC 9110 allows. Ranges which overlap or touch are merged, which RFC 9110 also allows, so asking for the same bytes over and over doesnt send them over and over.
+pub fn parse(header: &str, len: u64) -> Ranges {
+    let Some(specs) = parse_specs(header) else {
+        return Ranges::Full;
+    };
+    let mut ranges = Vec::new();
+    for (start, end) in specs {
+        let range = match (start, end) {
+            // The last `suffix` bytes
+            (None, Some(suffix)) => len
+                .checked_sub(1)
+                .filter(|_| suffix > 0)
+                .map(|last| (len.saturating_sub(suffix), last)),
+            (Some(start), end) => {
+                (start < len).then(|| (start, end.unwrap_or(u64::MAX).min(len - 1)))
+            }
+            (None, None) => return Ranges::Full,
+        };
+        ranges.extend(range);
+    }
+    if ranges.is_empty() {
+        return Ranges::Unsatisfiable;
+    }
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some((_, last_end)) if start <= last_end.saturating_add(1) => {
+                *last_end = (*last_end).max(end);
+            }
+            _ => merged.push((start, end)),
+        }
+    }
+    if merged.len() > MAX_RANGES {
+        Ranges::Full
+    } else {
+        Ranges::Partial(merged)
+    }
+}
+/// Splits `bytes=<start>-<end>,...` into its ranges, either end of which may be missing. Returns `None` if any range is malformed.
+fn parse_specs(header: &str) -> Option<Vec<(Option<u64>, Option<u64>)>> {
+    let specs = header.trim().strip_prefix("bytes=")?;
+    specs
+        .split(',')
+        .map(|spec| {
+            let (start, end) = spec.trim().split_once('-')?;
+            let start = (!start.is_empty())
+                .then(|| start.parse::<u64>())
+                .transpose()
+                .ok()?;
+            let end = (!end.is_empty())
+                .then(|| end.parse::<u64>())
+                .transpose()
+                .ok()?;
+            match (start, end) {
+                (Some(start), Some(end)) if end < start => None,
+                range => Some(range),
+            }
+        })
+        .collect()
+}
+/// The body of a `multipart/byteranges` response, each part being a few headers followed by its range of the file.
+pub struct Byteranges {
+    /// The headers starting each part, with the first byte and length of the range which follows them. Each range is followed by `\r\n`.
+    pub parts: Vec<(String, u64, u64)>,
+    /// The delimiter after the last part.
+    pub closing: String,
+}
+impl Byteranges {
+    pub fn new(boundary: &str, content_type: &str, ranges: &[(u64, u64)], len: u64) -> Self {
+        let parts = ranges
+            .iter()
+            .map(|&(start, end)| {
+                let part_head = format!("--{boundary}\r\nContent-Type: {content_type}\r\nContent-Range: bytes {start}-{end}/{len}\r\n\r\n");
+                (part_head, start, end - start + 1)
+            })
+            .collect();
+        Self {
+            parts,
+            closing: format!("--{boundary}--\r\n"),
+        }
+    }
+    /// How long the whole body is, for its `Content-Length`.
+    pub fn len(&self) -> u64 {
+        self.parts
+            .iter()
+            .map(|(part_head, _, count)| part_head.len() as u64 + count + 2)
+            .sum::<u64>()
+            + self.closing.len() as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_ranges() {
+        assert_eq!(parse("bytes=0-9", 100), Ranges::Partial(vec![(0, 9)]));
+        assert_eq!(parse("bytes=90-", 100), Ranges::Partial(vec![(90, 99)]));
+        // Ends past the file are cut to fit
+        assert_eq!(parse("bytes=50-500", 100), Ranges::Partial(vec![(50, 99)]));
+        assert_eq!(parse(" bytes= 0-0 ", 100), Ranges::Partial(vec![(0, 0)]));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse("bytes=-10", 100), Ranges::Partial(vec![(90, 99)]));
+        // A suffix longer than the file is the whole file
+        assert_eq!(parse("bytes=-500", 100), Ranges::Partial(vec![(0, 99)]));
+        assert_eq!(parse("bytes=-0", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=-10", 0), Ranges::Unsatisfiable);
+    }
+
+    #[test]
+    fn overlapping_ranges_are_merged() {
+        assert_eq!(
+            parse("bytes=0-10,5-20", 100),
+            Ranges::Partial(vec![(0, 20)])
+        );
+        // Touching ranges are merged too, and the result is in file order
+        assert_eq!(
+            parse("bytes=50-59,0-9,10-19", 100),
+            Ranges::Partial(vec![(0, 19), (50, 59)])
+        );
+        assert_eq!(
+            parse("bytes=0-,0-,0-,-100", 100),
+            Ranges::Partial(vec![(0, 99)])
+        );
+        assert_eq!(
+            parse("bytes=20-29,22-25", 100),
+            Ranges::Partial(vec![(20, 29)])
+        );
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(parse("bytes=100-", 100), Ranges::Unsatisfiable);
+        assert_eq!(parse("bytes=100-200,300-", 100), Ranges::Unsatisfiable);
+        // Only the ranges which can be served are
+        assert_eq!(
+            parse("bytes=100-200,0-1", 100),
+            Ranges::Partial(vec![(0, 1)])
+        );
+    }
+
+    #[test]
+    fn malformed_headers_are_ignored() {
+        assert_eq!(parse("bytes=5-2", 100), Ranges::Full);
+        assert_eq!(parse("bytes=0-1,5-2", 100), Ranges::Full);
+        assert_eq!(parse("bytes=-", 100), Ranges::Full);
+        assert_eq!(parse("bytes=a-b", 100), Ranges::Full);
+        assert_eq!(parse("items=0-1", 100), Ranges::Full);
+        assert_eq!(parse("bytes=0-1,", 100), Ranges::Full);
+        assert_eq!(parse("", 100), Ranges::Full);
+    }
+
+    #[test]
+    fn too_many_ranges() {
+        let ranges = |count: u64| {
+            let specs: Vec<String> = (0..count)
+                .map(|i| format!("{}-{}", i * 10, i * 10 + 1))
+                .collect();
+            format!("bytes={}", specs.join(","))
+        };
+        match parse(&ranges(MAX_RANGES as u64), 1000) {
+            Ranges::Partial(ranges) => assert_eq!(ranges.len(), MAX_RANGES),
+            ranges => panic!("expected {MAX_RANGES} ranges, got {ranges:?}"),
+        }
+        assert_eq!(parse(&ranges(MAX_RANGES as u64 + 1), 1000), Ranges::Full);
+        // Ranges which merge dont count against the limit
+        let repeated = vec!["0-1"; 100].join(",");
+        assert_eq!(
+            parse(&format!("bytes={repeated}"), 1000),
+            Ranges::Partial(vec![(0, 1)])
+        );
+    }
+
+    #[test]
+    fn byteranges_body() {
+        let file = b"0123456789";
+        let body = Byteranges::new("XYZ", "text/plain", &[(0, 1), (8, 9)], 10);
+        let mut sent = Vec::new();
+        for (part_head, start, count) in &body.parts {
+            sent.extend_from_slice(part_head.as_bytes());
+            sent.extend_from_slice(&file[*start as usize..(start + count) as usize]);
+            sent.extend_from_slice(b"\r\n");
+        }
+        sent.extend_from_slice(body.closing.as_bytes());
+        assert_eq!(
+            String::from_utf8(sent.clone()).unwrap(),
+            "--XYZ\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --XYZ\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n\
+             --XYZ--\r\n"
+        );
+        assert_eq!(body.len(), sent.len() as u64);
+    }
+}
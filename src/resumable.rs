@@ -0,0 +1,140 @@
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{self, Write},
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    sync::{LazyLock, Mutex, PoisonError},
+    time::{Duration, SystemTime},
+};
+
+use crate::{log, metadata::Metadata, FILES_PATH};
+
+/// The path resumable uploads are reached under, e.g. `/resumable/<token>`. It is one segment like an upload id, so uploads are never given it as their id.
+pub const RESUMABLE_PATH: &str = "resumable";
+/// The folder in the files directory holding resumable uploads until every chunk has arrived. Its name cant be an upload id, so storage never sees it.
+const PARTIAL_DIR: &str = ".resumable";
+const DATA_FILE: &str = "data";
+/// Tokens of the uploads a chunk is being written to, so two chunks cant be appended at once.
+static CLAIMED: LazyLock<Mutex<HashSet<String>>> = LazyLock::new(|| Mutex::new(HashSet::new()));
+
+/// An upload still arriving in chunks, kept as a folder named after its token holding the metadata and everything received so far. The token is only given to the uploader, so it is all that is needed to add to the upload.
+pub struct PartialUpload {
+    token: String,
+    dir: PathBuf,
+    pub metadata: Metadata,
+}
+/// Whether `token` could be a resumable upload's token, which keeps tokens safe to use in paths.
+fn valid_token(token: &str) -> bool {
+    !token.is_empty() && token.chars().all(|char| char.is_ascii_hexdigit())
+}
+impl PartialUpload {
+    /// Starts a resumable upload under `token`, whose `metadata` has its `upload_length` set.
+    pub fn create(token: &str, metadata: &Metadata) -> io::Result<Self> {
+        if !valid_token(token) {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let dir = FILES_PATH.join(PARTIAL_DIR).join(token);
+        std::fs::create_dir_all(FILES_PATH.join(PARTIAL_DIR))?;
+        std::fs::create_dir(&dir)?;
+        let created = metadata
+            .write(&dir)
+            .and_then(|()| File::create_new(dir.join(DATA_FILE)).map(drop));
+        if let Err(err) = created {
+            let _ = std::fs::remove_dir_all(&dir);
+            return Err(err);
+        }
+        Ok(Self {
+            token: token.to_owned(),
+            dir,
+            metadata: metadata.clone(),
+        })
+    }
+    /// # Errors
+    /// Returns a `NotFound` error if there is no resumable upload with that token.
+    pub fn open(token: &str) -> io::Result<Self> {
+        if !valid_token(token) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let dir = FILES_PATH.join(PARTIAL_DIR).join(token);
+        let metadata = Metadata::load(&dir)?;
+        Ok(Self {
+            token: token.to_owned(),
+            dir,
+            metadata,
+        })
+    }
+    /// How many bytes have arrived so far.
+    pub fn offset(&self) -> io::Result<u64> {
+        Ok(std::fs::metadata(self.dir.join(DATA_FILE))?.len())
+    }
+    /// The size the upload will be once complete.
+    pub fn length(&self) -> u64 {
+        self.metadata.upload_length.unwrap_or(0)
+    }
+    /// Stops anyone else appending to the upload until the returned claim is dropped, returning `None` if it is already claimed.
+    pub fn claim(&self) -> Option<Claim> {
+        let mut claimed = CLAIMED.lock().unwrap_or_else(PoisonError::into_inner);
+        claimed.insert(self.token.clone()).then(|| Claim {
+            token: self.token.clone(),
+        })
+    }
+    /// Opens the data for appending the next chunk. The caller should hold a [`Claim`].
+    pub fn append(&self) -> io::Result<impl Write> {
+        File::options().append(true).open(self.dir.join(DATA_FILE))
+    }
+    /// Opens everything received so far for reading.
+    pub fn data(&self) -> io::Result<File> {
+        File::open(self.dir.join(DATA_FILE))
+    }
+    /// Deletes the upload's folder, once it has been stored or abandoned.
+    pub fn remove(self) -> io::Result<()> {
+        std::fs::remove_dir_all(&self.dir)
+    }
+}
+/// Held while a chunk is appended to a resumable upload, see [`PartialUpload::claim`].
+pub struct Claim {
+    token: String,
+}
+impl Drop for Claim {
+    fn drop(&mut self) {
+        CLAIMED
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&self.token);
+    }
+}
+/// Deletes resumable uploads which havent had a chunk in `abandoned`, returning the tokens of those deleted. With `dry_run`, nothing is deleted.
+pub fn collect_abandoned(abandoned: Duration, dry_run: bool) -> io::Result<Vec<String>> {
+    let entries = match std::fs::read_dir(FILES_PATH.join(PARTIAL_DIR)) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()), // Nothing was ever resumed
+        Err(err) => return Err(err),
+    };
+    let mut removed = Vec::new();
+    for entry in entries.flatten() {
+        let token = String::from_utf8_lossy(entry.file_name().as_bytes()).into_owned();
+        let Ok(upload) = PartialUpload::open(&token) else {
+            log!("Skipping resumable upload \"{token}\" without metadata");
+            continue;
+        };
+        // Written to on every chunk, so it shows when the upload was last worked on
+        let last_chunk = std::fs::metadata(upload.dir.join(DATA_FILE))
+            .and_then(|data| data.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let idle = SystemTime::now()
+            .duration_since(last_chunk)
+            .unwrap_or_default();
+        if idle < abandoned {
+            continue;
+        }
+        let Some(_claim) = upload.claim() else {
+            continue; // A chunk is arriving right now
+        };
+        if !dry_run {
+            std::fs::remove_dir_all(&upload.dir)?;
+        }
+        removed.push(token);
+    }
+    Ok(removed)
+}
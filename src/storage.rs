@@ -0,0 +1,123 @@
+use std::{
+    collections::BTreeMap,
+    io::{self, Read, Seek, Write},
+    sync::{Mutex, OnceLock, PoisonError},
+};
+
+use crate::metadata::Metadata;
+
+mod dedup;
+mod local;
+mod memory;
+mod s3;
+
+pub use dedup::DedupStorage;
+pub use local::{LocalFile, LocalStorage, STATIC_DIR};
+pub use memory::MemoryStorage;
+pub use s3::S3Storage;
+
+static STORAGE: OnceLock<Box<dyn Storage>> = OnceLock::new();
+/// Held while changing an upload's metadata based on what it was, so two changes at once cant undo each other.
+static UPDATE_LOCK: Mutex<()> = Mutex::new(());
+/// How many downloads of each upload are being sent right now, see [`Sending`].
+static SENDING: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+/// Where uploads are kept. Each upload is a single file and its [`Metadata`], stored under a unique id.
+pub trait Storage: Send + Sync {
+    /// Claims `id` for a new upload, storing its metadata.
+    /// # Errors
+    /// Returns an `AlreadyExists` error if the id is taken.
+    fn create(&self, id: &str, metadata: &Metadata) -> io::Result<()>;
+    /// Opens the upload's file for writing. Nothing is guaranteed to be stored until [`FileWriter::finish`] is called.
+    fn put(&self, id: &str) -> io::Result<Box<dyn FileWriter>>;
+    /// Opens the upload's file for reading. It can still be read in full if the upload is deleted while open.
+    fn get(&self, id: &str) -> io::Result<Box<dyn StoredFile>>;
+    /// Deletes the upload's file and metadata.
+    fn delete(&self, id: &str) -> io::Result<()>;
+    /// The ids of every upload.
+    fn list(&self) -> io::Result<Vec<String>>;
+    /// Reads the upload's metadata.
+    /// # Errors
+    /// Returns a `NotFound` error if there is no such upload.
+    fn stat(&self, id: &str) -> io::Result<Metadata>;
+    /// Replaces the upload's metadata.
+    fn set_metadata(&self, id: &str, metadata: &Metadata) -> io::Result<()>;
+    /// Deletes anything stored which no upload needs any more, for backends which share data between uploads, returning what was deleted. With `dry_run`, nothing is deleted.
+    fn collect_garbage(&self, _dry_run: bool) -> io::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+/// An upload's file being written.
+pub trait FileWriter: Write + Send {
+    /// Finishes writing, making sure everything written is stored.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+/// An upload's file being read.
+pub trait StoredFile: Read + Seek + Send {
+    /// The file's size in bytes.
+    fn len(&self) -> u64;
+}
+
+/// Makes `storage` the backend returned by [`get`]. Only the first call has any effect.
+pub fn install(storage: Box<dyn Storage>) {
+    let _ = STORAGE.set(storage);
+}
+/// The storage backend uploads are kept in.
+/// # Panics
+/// Panics if called before [`install`].
+pub fn get() -> &'static dyn Storage {
+    STORAGE
+        .get()
+        .expect("Storage should be installed at startup")
+        .as_ref()
+}
+/// Whether `id` could be an upload's id, which keeps ids safe to use in paths and object keys.
+fn valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|char| char.is_ascii_alphanumeric() || char == '-' || char == '_')
+}
+/// Takes one download from an upload, returning whether that was the last one allowed. The upload is then refused to everyone else, and the caller should delete it once it has been sent, as some backends cant read what has been deleted.
+/// # Errors
+/// Returns a `NotFound` error if the upload has already been deleted or used up, which may have been by a download at the same time.
+pub fn count_download(storage: &dyn Storage, id: &str) -> io::Result<bool> {
+    let _lock = UPDATE_LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut metadata = storage.stat(id)?;
+    if metadata.is_used_up() {
+        return Err(io::ErrorKind::NotFound.into());
+    }
+    metadata.downloads += 1;
+    storage.set_metadata(id, &metadata)?;
+    Ok(metadata.is_used_up())
+}
+/// Marks a download of an upload as being sent until it is dropped, so the garbage collector leaves the upload alone. If it was the last download, the sender deletes the upload once it is done.
+pub struct Sending(String);
+impl Sending {
+    pub fn new(id: &str) -> Self {
+        *SENDING
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(id.to_owned())
+            .or_default() += 1;
+        Self(id.to_owned())
+    }
+}
+impl Drop for Sending {
+    fn drop(&mut self) {
+        let mut sending = SENDING.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = sending.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                sending.remove(&self.0);
+            }
+        }
+    }
+}
+/// Whether a download of `id` is being sent.
+pub fn is_sending(id: &str) -> bool {
+    SENDING
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .contains_key(id)
+}
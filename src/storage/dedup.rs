@@ -0,0 +1,303 @@
+use std::{
+    collections::HashSet,
+    io::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use sha2::{Digest, Sha256};
+
+use super::{FileWriter, Storage, StoredFile};
+use crate::{log, metadata::Metadata};
+
+/// Ids starting with this hold a file's data, named after the upload that first stored it.
+const BLOB_PREFIX: &str = "blob-";
+/// Ids starting with this point a file's digest at the blob holding it.
+const DIGEST_PREFIX: &str = "sha256-";
+
+/// Stores each distinct file once, in another backend. Uploads only hold metadata, naming the blob their file is in, and blobs are deleted by [`Storage::collect_garbage`] once no upload refers to them.
+pub struct DedupStorage {
+    inner: Arc<dyn Storage>,
+    /// Held while pointing an upload at a blob, and while looking for unused blobs, so a blob cant be deleted as it gains a reference.
+    link_lock: Arc<Mutex<()>>,
+}
+impl DedupStorage {
+    pub fn new(inner: Box<dyn Storage>) -> Self {
+        Self {
+            inner: Arc::from(inner),
+            link_lock: Arc::new(Mutex::new(())),
+        }
+    }
+}
+fn is_upload(id: &str) -> bool {
+    !id.starts_with(BLOB_PREFIX) && !id.starts_with(DIGEST_PREFIX)
+}
+impl Storage for DedupStorage {
+    fn create(&self, id: &str, metadata: &Metadata) -> io::Result<()> {
+        if !is_upload(id) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        self.inner.create(id, metadata)
+    }
+    fn put(&self, id: &str) -> io::Result<Box<dyn FileWriter>> {
+        let blob = format!("{BLOB_PREFIX}{id}");
+        // The upload refers to its blob before the blob exists, so the blob is never unreferenced while being written
+        let mut metadata = self.stat(id)?;
+        metadata.blob = Some(blob.clone());
+        self.inner.set_metadata(id, &metadata)?;
+        self.inner
+            .create(&blob, &Metadata::new("blob", Duration::ZERO))?;
+        Ok(Box::new(DedupWriter {
+            inner: self.inner.clone(),
+            link_lock: self.link_lock.clone(),
+            id: id.to_owned(),
+            blob: blob.clone(),
+            writer: self.inner.put(&blob)?,
+            hasher: Sha256::new(),
+        }))
+    }
+    fn get(&self, id: &str) -> io::Result<Box<dyn StoredFile>> {
+        let blob = self.stat(id)?.blob.ok_or(io::ErrorKind::NotFound)?;
+        self.inner.get(&blob)
+    }
+    fn delete(&self, id: &str) -> io::Result<()> {
+        if !is_upload(id) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        self.inner.delete(id) // The blob is left for the garbage collector, as other uploads may share it
+    }
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut ids = self.inner.list()?;
+        ids.retain(|id| is_upload(id));
+        Ok(ids)
+    }
+    fn stat(&self, id: &str) -> io::Result<Metadata> {
+        if !is_upload(id) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        self.inner.stat(id)
+    }
+    fn set_metadata(&self, id: &str, metadata: &Metadata) -> io::Result<()> {
+        let stored = self.stat(id)?;
+        let mut metadata = metadata.clone();
+        metadata.blob = stored.blob;
+        metadata.sha256 = metadata.sha256.or(stored.sha256);
+        self.inner.set_metadata(id, &metadata)
+    }
+    fn collect_garbage(&self, dry_run: bool) -> io::Result<Vec<String>> {
+        let _lock = self
+            .link_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // Listed before counting references, so any blob listed already has its first reference
+        let ids = self.inner.list()?;
+        let mut referenced = HashSet::new();
+        for id in ids.iter().filter(|id| is_upload(id)) {
+            match self.inner.stat(id) {
+                Ok(metadata) => referenced.extend(metadata.blob),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {} // Deleted since being listed
+                Err(err) => return Err(err), // Cant tell which blobs are in use
+            }
+        }
+        let mut unused = Vec::new();
+        for id in ids.iter().filter(|id| id.starts_with(DIGEST_PREFIX)) {
+            let blob = self.inner.stat(id)?.blob.unwrap_or_default();
+            if !referenced.contains(&blob) {
+                unused.push(id.clone());
+            }
+        }
+        // Digests go first, so nothing can find a blob after it is deleted
+        unused.extend(
+            ids.into_iter()
+                .filter(|id| id.starts_with(BLOB_PREFIX) && !referenced.contains(id)),
+        );
+        if !dry_run {
+            for id in &unused {
+                self.inner.delete(id)?;
+            }
+        }
+        Ok(unused)
+    }
+}
+/// Hashes a file as it is written to its own blob. Once finished, the blob is kept if its digest is new, otherwise the upload is pointed at the existing blob and the new one deleted.
+struct DedupWriter {
+    inner: Arc<dyn Storage>,
+    link_lock: Arc<Mutex<()>>,
+    id: String,
+    blob: String,
+    writer: Box<dyn FileWriter>,
+    hasher: Sha256,
+}
+impl Write for DedupWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.writer.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+impl FileWriter for DedupWriter {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let Self {
+            inner,
+            link_lock,
+            id,
+            blob,
+            writer,
+            hasher,
+        } = *self;
+        writer.finish()?;
+        let digest: String = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let digest_id = format!("{DIGEST_PREFIX}{digest}");
+        let _lock = link_lock.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut pointer = Metadata::new("blob", Duration::ZERO);
+        pointer.blob = Some(blob.clone());
+        pointer.sha256 = Some(digest.clone());
+        let shared_blob = match inner.create(&digest_id, &pointer) {
+            Ok(()) => blob.clone(),
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => inner
+                .stat(&digest_id)?
+                .blob
+                .ok_or_else(|| io::Error::other(format!("\"{digest_id}\" has no blob")))?,
+            Err(err) => return Err(err),
+        };
+        let mut metadata = inner.stat(&id)?;
+        metadata.blob = Some(shared_blob.clone());
+        metadata.sha256 = Some(digest);
+        inner.set_metadata(&id, &metadata)?;
+        if shared_blob != blob {
+            log!("\"{id}\" is a duplicate of \"{shared_blob}\"");
+            if let Err(err) = inner.delete(&blob) {
+                log!("Failed to delete duplicate \"{blob}\": {err}"); // Left for the garbage collector
+            }
+        }
+        Ok(())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    /// Dedup storage over memory storage, along with the memory storage to look at what it holds.
+    fn storage() -> (DedupStorage, Arc<MemoryStorage>) {
+        let inner = Arc::new(MemoryStorage::new());
+        let storage = DedupStorage {
+            inner: inner.clone(),
+            link_lock: Arc::new(Mutex::new(())),
+        };
+        (storage, inner)
+    }
+    fn upload(storage: &DedupStorage, id: &str, contents: &[u8]) {
+        storage
+            .create(id, &Metadata::new("file.txt", Duration::from_secs(60)))
+            .unwrap();
+        let mut file = storage.put(id).unwrap();
+        file.write_all(contents).unwrap();
+        file.finish().unwrap();
+    }
+    fn read(storage: &DedupStorage, id: &str) -> Vec<u8> {
+        let mut contents = Vec::new();
+        storage.get(id).unwrap().read_to_end(&mut contents).unwrap();
+        contents
+    }
+    fn stored(inner: &MemoryStorage) -> Vec<String> {
+        let mut ids = inner.list().unwrap();
+        ids.sort();
+        ids
+    }
+    fn digest(contents: &[u8]) -> String {
+        let digest: String = Sha256::digest(contents)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        format!("{DIGEST_PREFIX}{digest}")
+    }
+
+    #[test]
+    fn identical_uploads_share_a_blob() {
+        let (storage, inner) = storage();
+        upload(&storage, "aaa", b"same");
+        upload(&storage, "bbb", b"same");
+        upload(&storage, "ccc", b"other");
+        assert_eq!(
+            storage.stat("aaa").unwrap().blob.as_deref(),
+            Some("blob-aaa")
+        );
+        assert_eq!(
+            storage.stat("bbb").unwrap().blob.as_deref(),
+            Some("blob-aaa")
+        );
+        assert_eq!(
+            storage.stat("ccc").unwrap().blob.as_deref(),
+            Some("blob-ccc")
+        );
+        assert_eq!(read(&storage, "bbb"), b"same");
+        assert_eq!(read(&storage, "ccc"), b"other");
+        let mut expected = vec![
+            "aaa".to_owned(),
+            "bbb".to_owned(),
+            "blob-aaa".to_owned(),
+            "blob-ccc".to_owned(),
+            "ccc".to_owned(),
+            digest(b"same"),
+            digest(b"other"),
+        ];
+        expected.sort();
+        assert_eq!(stored(&inner), expected);
+        assert_eq!(storage.list().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn deleting_an_upload_keeps_a_shared_blob() {
+        let (storage, _) = storage();
+        upload(&storage, "aaa", b"same");
+        upload(&storage, "bbb", b"same");
+        storage.delete("aaa").unwrap();
+        assert_eq!(
+            storage.collect_garbage(false).unwrap(),
+            Vec::<String>::new()
+        );
+        assert_eq!(read(&storage, "bbb"), b"same");
+        // Blobs and digests arent uploads
+        for id in ["blob-aaa".to_owned(), digest(b"same")] {
+            assert_eq!(
+                storage.delete(&id).unwrap_err().kind(),
+                io::ErrorKind::NotFound
+            );
+        }
+    }
+
+    #[test]
+    fn unreferenced_blobs_are_collected() {
+        let (storage, inner) = storage();
+        upload(&storage, "aaa", b"same");
+        upload(&storage, "bbb", b"same");
+        upload(&storage, "ccc", b"other");
+        storage.delete("aaa").unwrap();
+        storage.delete("bbb").unwrap();
+        let unused = vec![digest(b"same"), "blob-aaa".to_owned()];
+        assert_eq!(storage.collect_garbage(true).unwrap(), unused);
+        assert_eq!(stored(&inner).len(), 5);
+        assert_eq!(storage.collect_garbage(false).unwrap(), unused);
+        let mut expected = vec!["blob-ccc".to_owned(), "ccc".to_owned(), digest(b"other")];
+        expected.sort();
+        assert_eq!(stored(&inner), expected);
+        assert_eq!(read(&storage, "ccc"), b"other");
+        // The same file uploaded again gets a new blob
+        upload(&storage, "ddd", b"same");
+        assert_eq!(
+            storage.stat("ddd").unwrap().blob.as_deref(),
+            Some("blob-ddd")
+        );
+        assert_eq!(read(&storage, "ddd"), b"same");
+    }
+}
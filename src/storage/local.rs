@@ -0,0 +1,199 @@
+use std::{
+    fs::File,
+    io::{self, Read, Seek, SeekFrom, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use super::{valid_id, FileWriter, Storage, StoredFile};
+use crate::{log, metadata::Metadata};
+
+/// A folder in the files directory which is served as is, rather than being an upload.
+pub const STATIC_DIR: &str = "static";
+
+/// Keeps each upload in its own folder, named after its id, holding the file and a metadata sidecar.
+pub struct LocalStorage {
+    root: PathBuf,
+    /// How long uploads found without metadata are kept, from when their folder was created.
+    rebuild_lifetime: Duration,
+    /// Held while writing metadata, so rebuilding it cant overwrite metadata stored at the same time.
+    write_lock: Mutex<()>,
+}
+impl LocalStorage {
+    pub fn new(root: PathBuf, rebuild_lifetime: Duration) -> Self {
+        Self {
+            root,
+            rebuild_lifetime,
+            write_lock: Mutex::new(()),
+        }
+    }
+    /// The folder holding an upload, refusing ids which could escape the root or name the static folder.
+    fn dir(&self, id: &str) -> io::Result<PathBuf> {
+        if !valid_id(id) || id == STATIC_DIR {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(self.root.join(id))
+    }
+    fn file_location(&self, id: &str) -> io::Result<PathBuf> {
+        let metadata = self.stat(id)?;
+        if metadata.file_name.is_empty() || metadata.file_name.starts_with('.') {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(self.dir(id)?.join(metadata.file_name))
+    }
+}
+impl Storage for LocalStorage {
+    fn create(&self, id: &str, metadata: &Metadata) -> io::Result<()> {
+        if id == STATIC_DIR {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        let dir_location = self.dir(id)?;
+        std::fs::create_dir(&dir_location)?;
+        let _lock = self
+            .write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        metadata.write(&dir_location).inspect_err(|_| {
+            let _ = std::fs::remove_dir_all(&dir_location);
+        })
+    }
+    fn put(&self, id: &str) -> io::Result<Box<dyn FileWriter>> {
+        let file = File::options()
+            .create_new(true)
+            .write(true)
+            .open(self.file_location(id)?)?;
+        Ok(Box::new(file))
+    }
+    fn get(&self, id: &str) -> io::Result<Box<dyn StoredFile>> {
+        Ok(Box::new(LocalFile::open(&self.file_location(id)?)?))
+    }
+    fn delete(&self, id: &str) -> io::Result<()> {
+        std::fs::remove_dir_all(self.dir(id)?)
+    }
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(std::fs::read_dir(&self.root)?
+            .flatten()
+            .filter(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()))
+            .map(|entry| String::from_utf8_lossy(entry.file_name().as_bytes()).into_owned())
+            .filter(|id| self.dir(id).is_ok())
+            .collect())
+    }
+    fn stat(&self, id: &str) -> io::Result<Metadata> {
+        let dir_location = self.dir(id)?;
+        match Metadata::load(&dir_location) {
+            Ok(metadata) => return Ok(metadata),
+            Err(err)
+                if dir_location.is_dir()
+                    && (err.kind() == io::ErrorKind::NotFound
+                        || err.kind() == io::ErrorKind::InvalidData) =>
+            {
+                log!(
+                    "Rebuilding metadata of \"{}\": {err}",
+                    dir_location.display()
+                );
+            }
+            Err(err) => return Err(err),
+        }
+        let _lock = self
+            .write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match Metadata::load(&dir_location) {
+            Ok(metadata) => Ok(metadata), // Stored while waiting for the lock
+            Err(_) => {
+                let metadata = Metadata::rebuild(&dir_location, self.rebuild_lifetime)?;
+                metadata.write(&dir_location)?;
+                Ok(metadata)
+            }
+        }
+    }
+    fn set_metadata(&self, id: &str, metadata: &Metadata) -> io::Result<()> {
+        let dir_location = self.dir(id)?;
+        if !dir_location.is_dir() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let _lock = self
+            .write_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        metadata.write(&dir_location)
+    }
+}
+impl FileWriter for File {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.sync_all()
+    }
+}
+/// A file on disk, which can also be used for files outside of any upload.
+pub struct LocalFile {
+    file: File,
+    len: u64,
+}
+impl LocalFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let file = File::open(path)?;
+        let len = file.metadata()?.len();
+        Ok(Self { file, len })
+    }
+}
+impl Read for LocalFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+impl Seek for LocalFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+impl StoredFile for LocalFile {
+    fn len(&self) -> u64 {
+        self.len
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lost_metadata_is_rebuilt() {
+        let root =
+            std::env::temp_dir().join(format!("poc_project-local-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        let storage = LocalStorage::new(root.clone(), Duration::from_secs(60));
+        let mut metadata = Metadata::new("kept.txt", Duration::from_secs(3600));
+        metadata.password_hash = Some("hash".to_owned());
+        metadata.max_downloads = Some(1);
+        for id in ["missing", "corrupt"] {
+            storage.create(id, &metadata).unwrap();
+            let mut file = storage.put(id).unwrap();
+            file.write_all(b"hello").unwrap();
+            file.finish().unwrap();
+            let meta = root.join(id).join(".meta");
+            if id == "missing" {
+                std::fs::remove_file(meta).unwrap();
+            } else {
+                std::fs::write(meta, "not = [toml").unwrap();
+            }
+            let rebuilt = storage.stat(id).unwrap();
+            assert!(rebuilt.rebuilt, "{id}");
+            assert_eq!(rebuilt.file_name, "kept.txt");
+            assert_eq!(rebuilt.size, 5);
+            assert_eq!(rebuilt.expires - rebuilt.created, 60);
+            // Whatever protected the upload is gone, so it cant be trusted
+            assert_eq!(rebuilt.password_hash, None);
+            assert_eq!(rebuilt.max_downloads, None);
+            // Written back, so it is only rebuilt once
+            assert!(Metadata::load(&root.join(id)).unwrap().rebuilt);
+        }
+        // A folder which isnt there isnt an upload
+        assert_eq!(
+            storage.stat("gone").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
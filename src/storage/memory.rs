@@ -0,0 +1,109 @@
+use std::{
+    collections::HashMap,
+    io::{self, Cursor, Write},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+};
+
+use super::{FileWriter, Storage, StoredFile};
+use crate::metadata::Metadata;
+
+/// Keeps uploads in memory, so they are lost when the server stops. Useful for testing without touching the files directory.
+#[derive(Default)]
+pub struct MemoryStorage {
+    uploads: Arc<Mutex<HashMap<String, MemoryUpload>>>,
+}
+struct MemoryUpload {
+    metadata: Metadata,
+    data: Arc<[u8]>,
+}
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    fn uploads(&self) -> MutexGuard<'_, HashMap<String, MemoryUpload>> {
+        self.uploads.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+impl Storage for MemoryStorage {
+    fn create(&self, id: &str, metadata: &Metadata) -> io::Result<()> {
+        let mut uploads = self.uploads();
+        if uploads.contains_key(id) {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        uploads.insert(
+            id.to_owned(),
+            MemoryUpload {
+                metadata: metadata.clone(),
+                data: Arc::from([]),
+            },
+        );
+        Ok(())
+    }
+    fn put(&self, id: &str) -> io::Result<Box<dyn FileWriter>> {
+        if !self.uploads().contains_key(id) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        Ok(Box::new(MemoryWriter {
+            uploads: self.uploads.clone(),
+            id: id.to_owned(),
+            data: Vec::new(),
+        }))
+    }
+    fn get(&self, id: &str) -> io::Result<Box<dyn StoredFile>> {
+        let data = self
+            .uploads()
+            .get(id)
+            .ok_or(io::ErrorKind::NotFound)?
+            .data
+            .clone();
+        Ok(Box::new(Cursor::new(data)))
+    }
+    fn delete(&self, id: &str) -> io::Result<()> {
+        self.uploads()
+            .remove(id)
+            .map(|_| ())
+            .ok_or(io::ErrorKind::NotFound.into())
+    }
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.uploads().keys().cloned().collect())
+    }
+    fn stat(&self, id: &str) -> io::Result<Metadata> {
+        self.uploads()
+            .get(id)
+            .map(|upload| upload.metadata.clone())
+            .ok_or(io::ErrorKind::NotFound.into())
+    }
+    fn set_metadata(&self, id: &str, metadata: &Metadata) -> io::Result<()> {
+        let mut uploads = self.uploads();
+        let upload = uploads.get_mut(id).ok_or(io::ErrorKind::NotFound)?;
+        upload.metadata = metadata.clone();
+        Ok(())
+    }
+}
+/// Collects a file in memory, only storing it once finished.
+struct MemoryWriter {
+    uploads: Arc<Mutex<HashMap<String, MemoryUpload>>>,
+    id: String,
+    data: Vec<u8>,
+}
+impl Write for MemoryWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.data.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+impl FileWriter for MemoryWriter {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        let mut uploads = self.uploads.lock().unwrap_or_else(PoisonError::into_inner);
+        let upload = uploads.get_mut(&self.id).ok_or(io::ErrorKind::NotFound)?;
+        upload.data = Arc::from(self.data);
+        Ok(())
+    }
+}
+impl StoredFile for Cursor<Arc<[u8]>> {
+    fn len(&self) -> u64 {
+        self.get_ref().len() as u64
+    }
+}
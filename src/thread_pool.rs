@@ -0,0 +1,154 @@
+use std::{
+    io::Write,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender, TrySendError},
+        Arc, Mutex,
+    },
+    thread,
+};
+
+use crate::log;
+
+/// A fixed set of worker threads which take jobs off a bounded queue and pass them to a shared handler.
+pub struct ThreadPool<T: Send + 'static> {
+    sender: SyncSender<T>,
+    workers: usize,
+    queued: Arc<AtomicUsize>,
+    busy: Arc<AtomicUsize>,
+}
+impl<T: Send + 'static> ThreadPool<T> {
+    /// Spawns `workers` threads named `name`, each calling `handler` on the jobs they receive. At most `queue_size` jobs can wait for a free worker.
+    /// # Errors
+    /// Returns an IO error if a worker thread fails to spawn.
+    pub fn new(
+        name: &str,
+        workers: usize,
+        queue_size: usize,
+        handler: impl Fn(T) + Send + Sync + 'static,
+    ) -> std::io::Result<Self> {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+        let queued = Arc::new(AtomicUsize::new(0));
+        let busy = Arc::new(AtomicUsize::new(0));
+        for _ in 0..workers {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+            let queued = queued.clone();
+            let busy = busy.clone();
+            thread::Builder::new()
+                .name(name.to_owned())
+                .spawn(move || Self::work(&receiver, handler.as_ref(), &queued, &busy))?;
+        }
+        Ok(Self {
+            sender,
+            workers,
+            queued,
+            busy,
+        })
+    }
+    /// Runs jobs until every sender is dropped. A panicking job is logged rather than taking the worker down with it.
+    fn work(
+        receiver: &Mutex<Receiver<T>>,
+        handler: &(impl Fn(T) + ?Sized),
+        queued: &AtomicUsize,
+        busy: &AtomicUsize,
+    ) {
+        loop {
+            let job = match receiver.lock() {
+                Ok(receiver) => receiver.recv(),
+                Err(_) => return, // Another worker panicked while holding the lock
+            };
+            let Ok(job) = job else {
+                return;
+            };
+            queued.fetch_sub(1, Ordering::SeqCst);
+            busy.fetch_add(1, Ordering::SeqCst);
+            if let Err(error) = panic::catch_unwind(AssertUnwindSafe(|| handler(job))) {
+                log!("Worker panicked! Panic message: {:?}", error);
+            }
+            busy.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+    /// Queues a job for the next free worker.
+    /// # Errors
+    /// Hands the job back if the queue is full, so the caller can turn it away.
+    pub fn submit(&self, job: T) -> Result<(), T> {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        match self.sender.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(job) | TrySendError::Disconnected(job)) => {
+                self.queued.fetch_sub(1, Ordering::SeqCst);
+                Err(job)
+            }
+        }
+    }
+    /// The number of worker threads.
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+    /// The number of jobs waiting for a free worker.
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+    /// The number of workers currently running a job.
+    pub fn busy(&self) -> usize {
+        self.busy.load(Ordering::SeqCst)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    fn wait_until(check: impl Fn() -> bool) {
+        let start = Instant::now();
+        while !check() {
+            assert!(start.elapsed() < Duration::from_secs(5), "Timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn full_queue_hands_jobs_back() {
+        // Each job holds its worker until it is told to finish
+        let pool = ThreadPool::new("Test", 1, 1, |finish: Receiver<()>| {
+            let _ = finish.recv();
+        })
+        .unwrap();
+        assert_eq!(pool.workers(), 1);
+        let (finish_first, first) = mpsc::channel();
+        let (finish_second, second) = mpsc::channel();
+        let (_, third) = mpsc::channel::<()>();
+        assert!(pool.submit(first).is_ok());
+        wait_until(|| pool.busy() == 1);
+        assert_eq!(pool.queued(), 0);
+        assert!(pool.submit(second).is_ok());
+        assert_eq!(pool.queued(), 1);
+        assert!(pool.submit(third).is_err());
+        assert_eq!(pool.queued(), 1);
+        finish_first.send(()).unwrap();
+        wait_until(|| pool.queued() == 0);
+        assert_eq!(pool.busy(), 1);
+        finish_second.send(()).unwrap();
+        wait_until(|| pool.busy() == 0);
+    }
+
+    #[test]
+    fn workers_survive_panics() {
+        let (done, finished) = mpsc::channel();
+        let done = Mutex::new(done);
+        let pool = ThreadPool::new("Test", 1, 4, move |job: u32| {
+            assert_ne!(job, 0, "Job 0 panics");
+            done.lock().unwrap().send(job).unwrap();
+        })
+        .unwrap();
+        pool.submit(0).unwrap();
+        pool.submit(1).unwrap();
+        assert_eq!(finished.recv_timeout(Duration::from_secs(5)), Ok(1));
+        wait_until(|| pool.busy() == 0);
+    }
+}